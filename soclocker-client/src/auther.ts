@@ -1,20 +1,117 @@
 import axios from "axios";
-import { UserResponse, AuthResponse } from "@/model.ts";
+import {
+  UserResponse,
+  AuthResponse,
+  PowChallenge,
+  ServerPublicKeysResponse
+} from "@/model.ts";
 import * as base64 from "@stablelib/base64";
 import nacl from "tweetnacl";
 
+/**
+ * Whether requests must carry a proof of work, which is assumed until the
+ * server reports a difficulty of zero.
+ */
+let workRequired = true;
+
+/**
+ * Requests an authentication token for the given user, to authorise a single
+ * request of the given action such as "login" or "create_post", and decrypts
+ * it.
+ */
 export async function getProof(
   username: string,
+  action: string,
   localSecretKey: Uint8Array
 ): Promise<Uint8Array> {
-  let serverPublicKeyB64: string = (await axios.get("/_/server_public_key"))
-    .data;
+  let auth: AuthResponse = (await withWork(headers =>
+    axios.get("/_/auth", {
+      params: {
+        username: username,
+        action: action
+      },
+      headers: headers
+    })
+  )).data;
+  return openToken(auth, localSecretKey);
+}
+
+/**
+ * Sends a request which may require a proof of work, given the headers which
+ * carry it. If none was sent because the server reported that proof of work is
+ * disabled, and the server now requires one, it is solved and the request is
+ * sent again.
+ */
+export async function withWork<T>(
+  send: (headers: { [header: string]: string }) => Promise<T>
+): Promise<T> {
+  let solved = workRequired;
+  try {
+    return await send(await getWorkHeaders());
+  } catch (e) {
+    if (solved || !e.response || e.response.status !== 428) {
+      throw e;
+    }
+    workRequired = true;
+    return await send(await getWorkHeaders());
+  }
+}
+
+/**
+ * Fetches a proof of work challenge from the server and solves it, returning
+ * the headers which carry the solution. Once the server reports a difficulty
+ * of zero, proof of work is disabled, and no further challenges are fetched.
+ */
+export async function getWorkHeaders(): Promise<{ [header: string]: string }> {
+  if (!workRequired) {
+    return {};
+  }
+  let challenge: PowChallenge = (await axios.get("/_/pow")).data;
+  if (challenge.difficulty === 0) {
+    workRequired = false;
+  }
+  let encoder = new TextEncoder();
+  for (let nonce = 0; ; nonce++) {
+    let solution = `${challenge.salt}:${nonce}`;
+    let digest: Uint8Array = nacl.hash(encoder.encode(solution));
+    if (leadingZeroBits(digest) >= challenge.difficulty) {
+      return { "X-Proof-Of-Work": solution };
+    }
+  }
+}
 
-  let auth: AuthResponse = (await axios.get("/_/auth", {
-    params: {
-      username: username
+/**
+ * Counts the zero bits at the start of a digest.
+ */
+function leadingZeroBits(digest: Uint8Array): number {
+  let bits = 0;
+  for (let byte of digest) {
+    if (byte !== 0) {
+      return bits + Math.clz32(byte) - 24;
     }
-  })).data;
+    bits += 8;
+  }
+  return bits;
+}
+
+/**
+ * Decrypts a token the server has sealed to the given secret key, as sent when
+ * authenticating or registering.
+ */
+export async function openToken(
+  auth: AuthResponse,
+  localSecretKey: Uint8Array
+): Promise<Uint8Array> {
+  let serverPublicKeys: ServerPublicKeysResponse = (await axios.get(
+    "/_/server_public_key"
+  )).data;
+
+  // Find the server key the token was sealed with
+  let serverKey = serverPublicKeys.keys.find(key => key.id === auth.keyId);
+  if (serverKey === undefined) {
+    throw new Error("Token was sealed with an unknown server key.");
+  }
+  let serverPublicKeyB64: string = serverKey.publicKey;
 
   // Decode base64 encoded data
   let encryptedToken: Uint8Array = base64.decode(auth.encryptedToken);
@@ -31,3 +128,39 @@ export async function getProof(
   }
   return box;
 }
+
+/**
+ * The actions the server accepts a session for. Every other action requires a
+ * fresh proof of identity.
+ */
+const SESSION_ACTIONS: string[] = [
+  "create_post",
+  "edit_post",
+  "rekey_post",
+  "add_readers",
+  "read_feed",
+  "hide_post"
+];
+
+/**
+ * Returns the proof of identity and headers needed to authorise a write of the
+ * given action as the given user, using their session while it is valid and
+ * accepted for the action, or otherwise a new proof.
+ */
+export async function getAuthorization(
+  username: string,
+  localSecretKey: Uint8Array,
+  session: string,
+  sessionExpires: number,
+  action: string
+): Promise<{ proof?: string; headers: { [header: string]: string } }> {
+  if (
+    session !== "" &&
+    Date.now() < sessionExpires &&
+    SESSION_ACTIONS.includes(action)
+  ) {
+    return { headers: { Authorization: `Bearer ${session}` } };
+  }
+  let proof: Uint8Array = await getProof(username, action, localSecretKey);
+  return { proof: base64.encode(proof), headers: {} };
+}
@@ -4,6 +4,34 @@
 export interface AuthResponse {
   encryptedToken: string;
   nonce: string;
+  keyId: number;
+}
+
+/**
+ * Represents the response of a GET request to the `pow` endpoint.
+ */
+export interface PowChallenge {
+  salt: string;
+  difficulty: number;
+  expires: string;
+}
+
+/**
+ * Represents the response of a GET request to the `server_public_key`
+ * endpoint.
+ */
+export interface ServerPublicKeysResponse {
+  activeKeyId: number;
+  keys: ServerPublicKey[];
+}
+
+/**
+ * Represents a single server public key, identified by its key ID.
+ */
+export interface ServerPublicKey {
+  id: number;
+  publicKey: string;
+  expires: string | null;
 }
 
 /**
@@ -14,3 +42,29 @@ export interface UserResponse {
   publicKey: string;
   username: string;
 }
+
+/**
+ * Represents the body of any failed request, where `error` is a stable code
+ * such as `auth_invalid` or `auth_expired`.
+ */
+export interface ErrorResponse {
+  error: string;
+  message: string;
+}
+
+/**
+ * Represents the response of a POST request to the `post` endpoint.
+ */
+export interface PostCreatedResponse {
+  postId: number;
+  failedReaders: string[];
+}
+
+/**
+ * Represents the response of a POST request to the `auth` endpoint when a
+ * session is requested.
+ */
+export interface SessionResponse {
+  session: string;
+  expires: string;
+}
@@ -3,7 +3,7 @@ import Vuex from "vuex";
 import axios from "axios";
 import nacl from "tweetnacl";
 import * as base64 from "@stablelib/base64";
-import { AuthResponse, UserResponse } from "@/model.ts";
+import { AuthResponse, SessionResponse, UserResponse } from "@/model.ts";
 import { getProof } from "@/auther.ts";
 
 Vue.use(Vuex);
@@ -14,6 +14,8 @@ export default new Vuex.Store({
     publicKey: new Uint8Array(),
     secretKey: new Uint8Array(),
     userId: 0,
+    session: "",
+    sessionExpires: 0,
     loginAttemptFails: 0,
     isLoggedIn: false
   },
@@ -27,6 +29,8 @@ export default new Vuex.Store({
       state.secretKey = new Uint8Array();
       state.publicKey = new Uint8Array();
       state.userId = 0;
+      state.session = "";
+      state.sessionExpires = 0;
       state.loginAttemptFails = 0;
       state.isLoggedIn = false;
     },
@@ -34,8 +38,8 @@ export default new Vuex.Store({
     /**
      * Logs the user into the website
      * @param state Internal vuex state.
-     * @param credentials Object containing the users username and their secret
-     *   key as a byte array.
+     * @param credentials Object containing the users username, their secret
+     *   key as a byte array, and their session.
      */
     login(
       state,
@@ -43,18 +47,23 @@ export default new Vuex.Store({
         username,
         publicKey,
         secretKey,
-        userId
+        userId,
+        session
       }: {
         username: string;
         publicKey: Uint8Array;
         secretKey: Uint8Array;
         userId: number;
+        session: SessionResponse;
       }
     ) {
       state.username = username;
       state.secretKey = secretKey;
       state.publicKey = publicKey;
       state.userId = userId;
+      state.session = session.session;
+      // The server gives the expiry time in UTC, without a time zone
+      state.sessionExpires = Date.parse(`${session.expires}Z`);
       state.loginAttemptFails = 0;
       state.isLoggedIn = true;
     },
@@ -96,18 +105,19 @@ export default new Vuex.Store({
     ) {
       try {
         let localSecretKey = base64.decode(secretKeyB64);
-        let box = await getProof(username, localSecretKey);
+        let box = await getProof(username, "login", localSecretKey);
 
         // Encode the decrypted token
         let decryptedToken: string = base64.encode(box);
 
-        // Ask the server to validate the token
-        let loginValid: boolean = (await axios.post("/_/auth", {
+        // Ask the server to validate the token, and issue a session
+        let session: SessionResponse = (await axios.post("/_/auth", {
           decryptedToken: decryptedToken,
-          username: username
+          username: username,
+          session: true
         })).data;
 
-        if (loginValid) {
+        if (session.session) {
           // If the validation succeeds, set the login information
           let user: UserResponse = (await axios.get("/_/user", {
             params: {
@@ -119,7 +129,8 @@ export default new Vuex.Store({
             username: username,
             publicKey: localPublicKey,
             secretKey: localSecretKey,
-            id: user.id
+            id: user.id,
+            session: session
           });
         } else {
           throw new Error("Login authentication was invalid");
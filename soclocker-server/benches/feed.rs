@@ -0,0 +1,165 @@
+//! Benchmarks loading pages of the NOA feed from a seeded SQLite database, in
+//! which one reader has been shared hundreds of posts, each with several other
+//! readers. Run with `cargo bench --no-default-features --features sqlite`.
+#![cfg(feature = "sqlite")]
+#![feature(test)]
+
+extern crate test;
+
+use diesel::{connection::SimpleConnection, Connection, SqliteConnection};
+use rocket::{
+    config::{Config, Environment, LoggingLevel, Value},
+    http::{ContentType, Header, Status},
+    local::Client,
+};
+use soclocker_server::{
+    build_rocket,
+    models::{NoaOuterResponse, SessionResponse},
+    ServerOptions,
+};
+use sodiumoxide::crypto::box_ as pkc;
+use std::{collections::HashMap, env, fs, path::PathBuf, process};
+use test::Bencher;
+
+/// The number of users, other than the reader, who author and read posts.
+const USERS: usize = 50;
+
+/// The number of posts shared with the reader.
+const POSTS: usize = 300;
+
+/// The number of readers of each post, including the reader.
+const READERS_PER_POST: usize = 6;
+
+/// A server running against a seeded throwaway database, which is removed when
+/// the server is dropped.
+struct SeededServer {
+    client: Client,
+    database: PathBuf,
+    session: String,
+}
+
+impl Drop for SeededServer {
+    fn drop(&mut self) { fs::remove_file(&self.database).ok(); }
+}
+
+impl SeededServer {
+    /// Builds the server against a new database named after `name`, seeds it,
+    /// and starts a session as the reader.
+    fn new(name: &str) -> SeededServer {
+        sodiumoxide::init().unwrap();
+        let database =
+            env::temp_dir().join(format!("soclocker-bench-{}-{}.sqlite", name, process::id()));
+        fs::remove_file(&database).ok();
+        let mut database_config = HashMap::new();
+        database_config.insert("url", Value::from(database.to_str().unwrap()));
+        let mut databases = HashMap::new();
+        databases.insert("core_db", Value::from(database_config));
+        let (_, server_secret) = pkc::gen_keypair();
+        let config = Config::build(Environment::Development)
+            .log_level(LoggingLevel::Critical)
+            .extra("databases", databases)
+            .extra("server_secret_key", base64::encode(&server_secret.0))
+            .finalize()
+            .unwrap();
+        let client = Client::new(build_rocket(config, &ServerOptions::default())).unwrap();
+
+        // The schema now exists, so the data is inserted directly, including
+        // an outstanding authentication token for the reader with a known
+        // decrypted form.
+        let conn = SqliteConnection::establish(database.to_str().unwrap()).unwrap();
+        conn.batch_execute(&seed_sql()).unwrap();
+
+        let session = {
+            let mut response = client
+                .post("/_/auth")
+                .header(ContentType::JSON)
+                .body(r#"{ "decryptedToken": "token", "username": "reader", "session": true }"#)
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str::<SessionResponse>(&response.body_string().unwrap()).unwrap()
+        };
+
+        SeededServer { client, database, session: session.session }
+    }
+
+    /// Fetches the page of the reader's feed following `cursor`, returning the
+    /// cursor of the next page.
+    fn feed(&self, cursor: Option<&str>) -> Option<String> {
+        let mut uri = "/_/noa?username=reader".to_string();
+        if let Some(cursor) = cursor {
+            uri += &format!("&cursor={}", cursor);
+        }
+        let mut response = self
+            .client
+            .get(uri)
+            .header(Header::new("Authorization", format!("Bearer {}", self.session)))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let feed: NoaOuterResponse =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        feed.next_cursor
+    }
+}
+
+/// Generates the SQL seeding the database. The reader has the ID 1, and post
+/// `i` is authored by the user with the ID `2 + i % USERS`, and shared with the
+/// reader and the users with the IDs following its author's.
+fn seed_sql() -> String {
+    let key = |i: usize| {
+        let mut key = [0; pkc::PUBLICKEYBYTES];
+        key[..2].copy_from_slice(&[i as u8, (i >> 8) as u8]);
+        base64::encode(&key)
+    };
+    let mut sql = String::from("BEGIN;\n");
+    sql += &format!("INSERT INTO Users VALUES (1, '{}', 'reader', 'reader');\n", key(0));
+    for user in 0..USERS {
+        sql += &format!(
+            "INSERT INTO Users VALUES ({}, '{}', 'user{2}', 'user{2}');\n",
+            user + 2,
+            key(user + 1),
+            user
+        );
+    }
+    sql += &format!(
+        "INSERT INTO Auth VALUES (1, '{}', 'login', 'token', '2100-01-01 00:00:00');\n",
+        key(0)
+    );
+    for post in 0..POSTS {
+        let author = post % USERS;
+        let time_posted = format!("datetime('now', '-{} minutes')", post);
+        sql += &format!(
+            "INSERT INTO Posts VALUES ({}, 'content', 'nonce', {}, {}, 'key', 'nonce');\n",
+            post + 1,
+            author + 2,
+            time_posted
+        );
+        sql += &format!("INSERT INTO NOA VALUES (1, {}, 'key', 'nonce');\n", post + 1);
+        for reader in 1..READERS_PER_POST {
+            let reader = (author + reader) % USERS;
+            sql += &format!(
+                "INSERT INTO NOA VALUES ({}, {}, 'key', 'nonce');\n",
+                reader + 2,
+                post + 1
+            );
+        }
+    }
+    sql + "COMMIT;\n"
+}
+
+#[bench]
+fn feed_first_page(b: &mut Bencher) {
+    let server = SeededServer::new("first");
+    b.iter(|| server.feed(None));
+}
+
+#[bench]
+fn feed_last_page(b: &mut Bencher) {
+    let server = SeededServer::new("last");
+    let mut cursor = server.feed(None);
+    let mut last = None;
+    while let Some(next) = cursor {
+        cursor = server.feed(Some(&next));
+        last = Some(next);
+    }
+    b.iter(|| server.feed(last.as_ref().map(String::as_str)));
+}
@@ -0,0 +1,4 @@
+DROP TABLE `Auth`;
+DROP TABLE `NOA`;
+DROP TABLE `Posts`;
+DROP TABLE `Users`;
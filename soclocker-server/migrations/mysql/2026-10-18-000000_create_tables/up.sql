@@ -1,20 +1,19 @@
-DROP TABLE IF EXISTS `Users`;
+-- Creates the initial tables. These use `IF NOT EXISTS` so that databases
+-- created from the former `datastores/schema.sql` are adopted without loss.
 CREATE TABLE IF NOT EXISTS `Users` (
     `ID` INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT UNIQUE,
     `PublicKey` CHAR(44) NOT NULL UNIQUE,
     `Username` VARCHAR(100) NOT NULL UNIQUE
 );
-DROP TABLE IF EXISTS `Posts`;
 CREATE TABLE IF NOT EXISTS `Posts` (
     `ID` INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT UNIQUE,
     `Content` TEXT NOT NULL,
     `Nonce` TEXT NOT NULL,
     `UserID` INTEGER NOT NULL,
-    `TimePosted` TEXT NOT NULL,
+    `TimePosted` DATETIME NOT NULL,
     `PublicKey` TEXT NOT NULL,
     `PublicKeyNonce` TEXT NOT NULL
 );
-DROP TABLE IF EXISTS `NOA`;
 CREATE TABLE IF NOT EXISTS `NOA` (
     `UserID` INTEGER NOT NULL,
     `PostID` INTEGER NOT NULL,
@@ -22,9 +21,8 @@ CREATE TABLE IF NOT EXISTS `NOA` (
     `Nonce` TEXT NOT NULL,
     PRIMARY KEY(`PostID`,`UserID`)
 );
-DROP TABLE IF EXISTS `Auth`;
 CREATE TABLE IF NOT EXISTS `Auth` (
     `PublicKey` CHAR(44) NOT NULL PRIMARY KEY UNIQUE,
     `ExpectedToken` TEXT NOT NULL,
-    `Timeout` TEXT NOT NULL
+    `Timeout` DATETIME NOT NULL
 );
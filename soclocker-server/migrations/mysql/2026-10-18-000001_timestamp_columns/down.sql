@@ -0,0 +1,2 @@
+ALTER TABLE `Auth` MODIFY `Timeout` TEXT NOT NULL;
+ALTER TABLE `Posts` MODIFY `TimePosted` TEXT NOT NULL;
@@ -0,0 +1,5 @@
+-- Databases created from the former `datastores/schema.sql` stored timestamps
+-- as `TEXT`, rather than the `Timestamp` declared in `schema.rs`. Existing
+-- values are converted in place. This is a no-op for newly created tables.
+ALTER TABLE `Posts` MODIFY `TimePosted` DATETIME NOT NULL;
+ALTER TABLE `Auth` MODIFY `Timeout` DATETIME NOT NULL;
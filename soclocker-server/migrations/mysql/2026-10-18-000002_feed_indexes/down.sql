@@ -0,0 +1,2 @@
+DROP INDEX `NOA_UserID` ON `NOA`;
+DROP INDEX `Posts_TimePosted` ON `Posts`;
@@ -0,0 +1,4 @@
+-- The feed loads the NOAs of a single reader, newest post first, which the
+-- primary key of `NOA`, leading with `PostID`, cannot serve.
+CREATE INDEX `NOA_UserID` ON `NOA` (`UserID`);
+CREATE INDEX `Posts_TimePosted` ON `Posts` (`TimePosted`);
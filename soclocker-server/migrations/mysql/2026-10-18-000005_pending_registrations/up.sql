@@ -0,0 +1,10 @@
+-- Registrations awaiting proof that the registrant holds the secret key of the
+-- public key they submitted, which they give by returning `ExpectedToken`
+-- decrypted before `Timeout`.
+CREATE TABLE `PendingRegistrations` (
+    `PublicKey` CHAR(44) NOT NULL PRIMARY KEY,
+    `Username` VARCHAR(100) NOT NULL,
+    `UsernameKey` VARCHAR(100) NOT NULL,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` DATETIME NOT NULL
+);
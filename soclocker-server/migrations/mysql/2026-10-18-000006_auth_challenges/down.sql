@@ -0,0 +1,6 @@
+DROP TABLE `Auth`;
+CREATE TABLE `Auth` (
+    `PublicKey` CHAR(44) NOT NULL PRIMARY KEY UNIQUE,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` DATETIME NOT NULL
+);
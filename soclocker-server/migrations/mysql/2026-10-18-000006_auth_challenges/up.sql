@@ -0,0 +1,12 @@
+-- Each authentication challenge is now a row of its own, bound to the action
+-- it authorises, so that a user may hold several at once and each is consumed
+-- by exactly one use. Outstanding challenges are discarded.
+DROP TABLE `Auth`;
+CREATE TABLE `Auth` (
+    `ID` INTEGER NOT NULL PRIMARY KEY AUTO_INCREMENT UNIQUE,
+    `PublicKey` CHAR(44) NOT NULL,
+    `Action` VARCHAR(20) NOT NULL,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` DATETIME NOT NULL
+);
+CREATE INDEX `Auth_PublicKey_Action` ON `Auth` (`PublicKey`, `Action`);
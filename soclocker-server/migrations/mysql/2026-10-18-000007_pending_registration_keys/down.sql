@@ -0,0 +1,8 @@
+DROP TABLE `PendingRegistrations`;
+CREATE TABLE `PendingRegistrations` (
+    `PublicKey` CHAR(44) NOT NULL PRIMARY KEY,
+    `Username` VARCHAR(100) NOT NULL,
+    `UsernameKey` VARCHAR(100) NOT NULL,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` DATETIME NOT NULL
+);
@@ -0,0 +1,12 @@
+-- Pending registrations are now keyed by the username and public key together,
+-- so that registering a public key under another username never replaces a
+-- registration awaiting confirmation. Outstanding registrations are discarded.
+DROP TABLE `PendingRegistrations`;
+CREATE TABLE `PendingRegistrations` (
+    `PublicKey` CHAR(44) NOT NULL,
+    `Username` VARCHAR(100) NOT NULL,
+    `UsernameKey` VARCHAR(100) NOT NULL,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` DATETIME NOT NULL,
+    PRIMARY KEY (`UsernameKey`, `PublicKey`)
+);
@@ -0,0 +1,29 @@
+-- Creates the initial tables. These use `IF NOT EXISTS` so that existing
+-- development databases, such as `datastores/devel.sqlite`, are adopted
+-- without loss.
+CREATE TABLE IF NOT EXISTS `Users` (
+    `ID` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+    `PublicKey` TEXT NOT NULL UNIQUE,
+    `Username` TEXT NOT NULL UNIQUE
+);
+CREATE TABLE IF NOT EXISTS `Posts` (
+    `ID` INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT UNIQUE,
+    `Content` TEXT NOT NULL,
+    `Nonce` TEXT NOT NULL,
+    `UserID` INTEGER NOT NULL,
+    `TimePosted` TIMESTAMP NOT NULL,
+    `PublicKey` TEXT NOT NULL,
+    `PublicKeyNonce` TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS `NOA` (
+    `UserID` INTEGER NOT NULL,
+    `PostID` INTEGER NOT NULL,
+    `SecretKey` TEXT NOT NULL,
+    `Nonce` TEXT NOT NULL,
+    PRIMARY KEY(`PostID`,`UserID`)
+);
+CREATE TABLE IF NOT EXISTS `Auth` (
+    `PublicKey` TEXT NOT NULL PRIMARY KEY UNIQUE,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` TIMESTAMP NOT NULL
+);
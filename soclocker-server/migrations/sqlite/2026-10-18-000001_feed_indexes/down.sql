@@ -0,0 +1,2 @@
+DROP INDEX `NOA_UserID`;
+DROP INDEX `Posts_TimePosted`;
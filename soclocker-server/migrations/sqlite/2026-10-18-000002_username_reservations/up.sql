@@ -0,0 +1,7 @@
+-- The usernames users have renamed from, which no one else may take until
+-- `Expires`. Until then, lookups by them redirect to the user's new username.
+CREATE TABLE `UsernameReservations` (
+    `Username` TEXT NOT NULL PRIMARY KEY,
+    `UserID` INTEGER NOT NULL,
+    `Expires` TIMESTAMP NOT NULL
+);
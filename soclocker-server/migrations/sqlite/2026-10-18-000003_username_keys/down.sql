@@ -0,0 +1,2 @@
+DROP INDEX `Users_UsernameKey`;
+ALTER TABLE `Users` DROP COLUMN `UsernameKey`;
@@ -0,0 +1,7 @@
+-- Usernames must be unique once case folded, which is enforced on their key.
+-- Existing usernames are assumed to be ASCII, which `LOWER` folds, and this
+-- fails if any differ only in case. Reservations now hold keys too.
+ALTER TABLE `Users` ADD COLUMN `UsernameKey` TEXT NOT NULL DEFAULT '';
+UPDATE `Users` SET `UsernameKey` = LOWER(`Username`);
+CREATE UNIQUE INDEX `Users_UsernameKey` ON `Users` (`UsernameKey`);
+UPDATE `UsernameReservations` SET `Username` = LOWER(`Username`);
@@ -0,0 +1,10 @@
+-- Registrations awaiting proof that the registrant holds the secret key of the
+-- public key they submitted, which they give by returning `ExpectedToken`
+-- decrypted before `Timeout`.
+CREATE TABLE `PendingRegistrations` (
+    `PublicKey` TEXT NOT NULL PRIMARY KEY,
+    `Username` TEXT NOT NULL,
+    `UsernameKey` TEXT NOT NULL,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` TIMESTAMP NOT NULL
+);
@@ -0,0 +1,6 @@
+DROP TABLE `Auth`;
+CREATE TABLE `Auth` (
+    `PublicKey` TEXT NOT NULL PRIMARY KEY UNIQUE,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` TIMESTAMP NOT NULL
+);
@@ -0,0 +1,8 @@
+DROP TABLE `PendingRegistrations`;
+CREATE TABLE `PendingRegistrations` (
+    `PublicKey` TEXT NOT NULL PRIMARY KEY,
+    `Username` TEXT NOT NULL,
+    `UsernameKey` TEXT NOT NULL,
+    `ExpectedToken` TEXT NOT NULL,
+    `Timeout` TIMESTAMP NOT NULL
+);
@@ -0,0 +1,184 @@
+//! This module contains the authentication challenges issued by the `auth`
+//! endpoint, which are either stored in the `Auth` table or, in stateless mode,
+//! authenticated by the server rather than stored.
+//!
+//! A stateless challenge token is the user's ID, the token's expiry time and
+//! random bytes, followed by a tag authenticating them along with the action
+//! the token authorises and the user's public key under the server's challenge
+//! key. It is sealed to the user as stored tokens are, so proves their identity
+//! in the same way, but is checked without writing to the database. Rotating
+//! the user's key invalidates their outstanding tokens.
+//!
+//! Each token is still accepted only once by a server, by remembering the
+//! random bytes identifying those used until they expire. This replay cache is
+//! held in memory, so is not shared between servers. Servers sharing a
+//! challenge key, as set by the `challenge_key` configuration key, accept each
+//! other's tokens, but each may accept a token once, so stored challenges
+//! should be used where a token must be accepted once across every server.
+//! The mode is chosen by setting `auth_challenges` to `"database"`, the
+//! default, or `"stateless"`.
+
+use crate::{error::ApiError, routes::auth::Action, TIMEOUT_SECONDS};
+use chrono::{Duration, NaiveDateTime};
+use rocket::fairing::{AdHoc, Fairing};
+use sodiumoxide::{crypto::auth, randombytes::randombytes};
+use std::{collections::HashMap, convert::TryInto, sync::Mutex};
+
+/// The Rocket configuration key selecting how challenges are kept, either
+/// `"database"` or `"stateless"`.
+pub const AUTH_CHALLENGES_CONFIG: &str = "auth_challenges";
+
+/// The Rocket configuration key containing the base64 encoded challenge key.
+/// This should be set when several servers share stateless challenges, so that
+/// each accepts the tokens issued by the others. Otherwise a new challenge key
+/// is generated at launch, invalidating every outstanding stateless token.
+pub const CHALLENGE_KEY_CONFIG: &str = "challenge_key";
+
+/// The number of random bytes in each token, making each unique.
+const RANDOM_BYTES: usize = 16;
+
+/// The number of used tokens beyond which those which have expired are
+/// discarded.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The length of the claims of a token: a 4 byte user ID, an 8 byte expiry
+/// time and the random bytes.
+const CLAIMS_BYTES: usize = 4 + 8 + RANDOM_BYTES;
+
+/// The length of a token before it is sealed: its claims and the
+/// authentication tag.
+const TOKEN_BYTES: usize = CLAIMS_BYTES + auth::TAGBYTES;
+
+/// How authentication challenges are kept between being issued and used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChallengeMode {
+    /// Tokens are stored in the `Auth` table, and deleted when used.
+    Database,
+
+    /// Tokens are authenticated by the server, and remembered in memory once
+    /// used.
+    Stateless,
+}
+
+/// The challenge mode, and the key and replay cache of stateless challenges.
+#[derive(Debug)]
+pub struct Challenges {
+    /// How challenges are kept.
+    pub mode: ChallengeMode,
+
+    /// The key stateless tokens are authenticated with.
+    key: auth::Key,
+
+    /// The random bytes of the stateless tokens which have been used, with the
+    /// times they expire.
+    spent: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl Challenges {
+    /// Constructs a fairing which loads the challenge mode and key from the
+    /// Rocket configuration on attach, generating a key if none is configured,
+    /// and manages them for use by the routes. Launch is aborted if either is
+    /// not valid.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::on_attach("Authentication Challenges", |rocket| {
+            let mode = match rocket.config().get_str(AUTH_CHALLENGES_CONFIG) {
+                Ok("database") | Err(_) => Some(ChallengeMode::Database),
+                Ok("stateless") => Some(ChallengeMode::Stateless),
+                Ok(_) => None,
+            };
+            let key = match rocket.config().get_str(CHALLENGE_KEY_CONFIG) {
+                Ok(key) => base64::decode(key).ok().and_then(|key| auth::Key::from_slice(&key)),
+                Err(_) => Some(auth::gen_key()),
+            };
+            match (mode, key) {
+                (Some(mode), Some(key)) => {
+                    Ok(rocket.manage(Challenges { mode, key, spent: Mutex::new(HashMap::new()) }))
+                },
+                (None, _) => {
+                    rocket::logger::error(
+                        "The authentication challenge mode must be `database` or `stateless`.",
+                    );
+                    Err(rocket)
+                },
+                (_, None) => {
+                    rocket::logger::error("The challenge key is not a valid base64 encoded key.");
+                    Err(rocket)
+                },
+            }
+        })
+    }
+
+    /// Issues a stateless token for the user with the ID `user_id` and the
+    /// base64 encoded `public_key` to perform `action`, returning it base64
+    /// encoded.
+    pub fn issue(
+        &self,
+        user_id: i32,
+        public_key: &str,
+        action: Action,
+        now: NaiveDateTime,
+    ) -> String {
+        let expires = now + Duration::seconds(TIMEOUT_SECONDS);
+        let mut token = Vec::with_capacity(TOKEN_BYTES);
+        token.extend_from_slice(&user_id.to_be_bytes());
+        token.extend_from_slice(&expires.timestamp().to_be_bytes());
+        token.extend_from_slice(&randombytes(RANDOM_BYTES));
+        let tag = auth::authenticate(&message(&token, public_key, action), &self.key);
+        token.extend_from_slice(&tag.0);
+        base64::encode(&token)
+    }
+
+    /// Checks that `token` is a stateless token issued by this server, or one
+    /// sharing its key, for the user with the ID `user_id` and the base64
+    /// encoded `public_key` to perform `action`, and that it has not expired at
+    /// `now` or been used, and marks it used.
+    pub fn verify(
+        &self,
+        token: &str,
+        user_id: i32,
+        public_key: &str,
+        action: Action,
+        now: NaiveDateTime,
+    ) -> Result<(), ApiError> {
+        let token = base64::decode(token).map_err(|_| ApiError::AuthInvalid)?;
+        if token.len() != TOKEN_BYTES {
+            return Err(ApiError::AuthInvalid);
+        }
+        let (claims, tag) = token.split_at(CLAIMS_BYTES);
+        let tag = auth::Tag::from_slice(tag).ok_or(ApiError::AuthInvalid)?;
+        if !auth::verify(&tag, &message(claims, public_key, action), &self.key) {
+            return Err(ApiError::AuthInvalid);
+        }
+        if i32::from_be_bytes(claims[..4].try_into().unwrap()) != user_id {
+            return Err(ApiError::AuthInvalid);
+        }
+
+        // Used tokens are kept until they expire, when they would be refused
+        // anyway, and discarded then only once there are many, so that each
+        // verification does not scan them all.
+        let expires = i64::from_be_bytes(claims[4..12].try_into().unwrap());
+        let mut spent = self.spent.lock().unwrap_or_else(|e| e.into_inner());
+        if spent.len() > PRUNE_THRESHOLD {
+            spent.retain(|_, expires| *expires >= now.timestamp());
+        }
+        if spent.insert(claims[12..].to_vec(), expires).is_some() {
+            return Err(ApiError::AuthInvalid);
+        }
+        if expires < now.timestamp() {
+            return Err(ApiError::AuthExpired);
+        }
+        Ok(())
+    }
+}
+
+/// The message authenticated by a stateless token's tag: its `claims`, the name
+/// of the `action` it authorises and the `public_key` it is sealed to. The
+/// action name is terminated by a zero byte, as the other parts are of fixed
+/// length.
+fn message(claims: &[u8], public_key: &str, action: Action) -> Vec<u8> {
+    let mut message = claims.to_vec();
+    message.extend_from_slice(action.name().as_bytes());
+    message.push(0);
+    message.extend_from_slice(public_key.as_bytes());
+    message
+}
@@ -1,11 +1,90 @@
-//! This module contains the database connection structure.
+//! This module contains the database connection structure, and the migrations
+//! which create and upgrade the database schema.
 
+use diesel::{dsl::sql, sql_types::BigInt, QueryResult, RunQueryDsl};
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    Rocket,
+};
 use rocket_contrib::database;
 
-use diesel::MysqlConnection;
+#[cfg(all(feature = "mysql", feature = "sqlite"))]
+compile_error!("only one of the `mysql` and `sqlite` features may be enabled");
 
-/// Constructs the Database Connection from the supplied configuration. During
-/// development this is an SqliteConnection, however it will be swapped for a
-/// MySqlConnection closer to deployment.
+#[cfg(not(any(feature = "mysql", feature = "sqlite")))]
+compile_error!("one of the `mysql` or `sqlite` features must be enabled");
+
+/// The Diesel connection type of the database backend selected by the `mysql`
+/// or `sqlite` cargo feature.
+#[cfg(feature = "mysql")]
+pub type Connection = diesel::MysqlConnection;
+
+/// The Diesel connection type of the database backend selected by the `mysql`
+/// or `sqlite` cargo feature.
+#[cfg(feature = "sqlite")]
+pub type Connection = diesel::SqliteConnection;
+
+// The migrations for the selected backend are embedded in the binary, as
+// `embedded_migrations`.
+#[cfg(feature = "mysql")]
+embed_migrations!("migrations/mysql");
+#[cfg(feature = "sqlite")]
+embed_migrations!("migrations/sqlite");
+
+/// The Rocket configuration key which, when set to `false`, disables running
+/// pending migrations at launch. They must then be run with
+/// `soclocker-server migrate`.
+pub const RUN_MIGRATIONS_CONFIG: &str = "run_migrations";
+
+/// Constructs the Database Connection from the supplied configuration. This is
+/// a MysqlConnection in deployment, or an SqliteConnection during development
+/// when built with the `sqlite` feature.
 #[database("core_db")]
-pub struct CoreDbConn(MysqlConnection);
+pub struct CoreDbConn(Connection);
+
+impl CoreDbConn {
+    /// Constructs a fairing which runs any pending migrations on attach,
+    /// unless disabled by `RUN_MIGRATIONS_CONFIG`. Launch is aborted if the
+    /// migrations fail. Must be attached after `CoreDbConn::fairing()`.
+    pub fn migrations_fairing() -> impl Fairing {
+        AdHoc::on_attach("Database Migrations", |rocket| {
+            if !rocket.config().get_bool(RUN_MIGRATIONS_CONFIG).unwrap_or(true) {
+                return Ok(rocket);
+            }
+            match migrate(&rocket) {
+                Ok(()) => Ok(rocket),
+                Err(e) => {
+                    rocket::logger::error("Failed to run database migrations.");
+                    rocket::logger::error_(&e);
+                    Err(rocket)
+                },
+            }
+        })
+    }
+}
+
+/// The SQL function returning the ID of the last row inserted on a connection.
+#[cfg(feature = "mysql")]
+const LAST_INSERT_ID: &str = "LAST_INSERT_ID()";
+#[cfg(feature = "sqlite")]
+const LAST_INSERT_ID: &str = "last_insert_rowid()";
+
+/// Returns the ID of the last row inserted on `conn`. Neither backend supports
+/// `RETURNING`, so this must be called immediately after the insert, on the
+/// same connection.
+pub fn last_insert_id(conn: &Connection) -> QueryResult<i32> {
+    // Row IDs are `INTEGER` columns, so always fit.
+    diesel::select(sql::<BigInt>(LAST_INSERT_ID)).get_result::<i64>(conn).map(|id| id as i32)
+}
+
+/// Runs any pending migrations against the database managed by `rocket`,
+/// logging each migration which is run.
+pub fn migrate(rocket: &Rocket) -> Result<(), String> {
+    let conn = CoreDbConn::get_one(rocket).ok_or("no database connection is available")?;
+    let mut output = Vec::new();
+    let result = embedded_migrations::run_with_output(&conn.0, &mut output);
+    for line in String::from_utf8_lossy(&output).lines() {
+        log::info!("{}", line);
+    }
+    result.map_err(|e| e.to_string())
+}
@@ -0,0 +1,275 @@
+//! This module contains the error type returned by the API routes, and the
+//! catchers which give Rocket's own errors the same JSON form.
+
+use crate::models::ErrorResponse;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use rocket::{
+    catch,
+    catchers,
+    http::{uri::Uri, Status},
+    response::{self, status, Responder},
+    Catcher,
+    Request,
+};
+use rocket_contrib::json::Json;
+use std::fmt;
+
+/// An error returned by an API route. This responds with the appropriate
+/// status, and a body of
+///
+/// ```json
+/// {
+///     error: "auth_expired",
+///     message: "..."
+/// }
+/// ```
+///
+/// where `error` is a stable machine readable code, as given by
+/// `ApiError::code`, and `message` is a human readable description.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request was malformed.
+    BadRequest,
+
+    /// The username supplied is not valid.
+    InvalidUsername,
+
+    /// The public key supplied is not valid.
+    InvalidPublicKey,
+
+    /// No route or resource matched the request.
+    NotFound,
+
+    /// The user named in the request does not exist.
+    UserNotFound,
+
+    /// The username in the request is reserved by a user who has since been
+    /// renamed to the username given. This redirects to the same URI with its
+    /// `username` parameter replaced.
+    UserRenamed(String),
+
+    /// The post named in the request does not exist.
+    PostNotFound,
+
+    /// The request requires proof of identity, and none was supplied.
+    AuthRequired,
+
+    /// The request requires a fresh proof of identity, and only a session was
+    /// supplied.
+    ProofRequired,
+
+    /// The proof of identity supplied did not match an outstanding
+    /// authentication token.
+    AuthInvalid,
+
+    /// The proof of identity supplied matched an authentication token which
+    /// has timed out.
+    AuthExpired,
+
+    /// The username requested for registration is already in use.
+    UsernameTaken,
+
+    /// The request conflicts with existing data.
+    Conflict,
+
+    /// Too many requests have been made, and another may be made after the
+    /// given number of seconds. This responds with the `Retry-After` header.
+    RateLimited(u64),
+
+    /// The request requires a proof of work, and none was supplied.
+    WorkRequired,
+
+    /// The proof of work supplied does not solve a challenge issued by the
+    /// server, or solves one which has expired or already been spent.
+    WorkInvalid,
+
+    /// No database connection could be acquired.
+    DbUnavailable,
+
+    /// A database query failed.
+    Database(DieselError),
+
+    /// The server failed in an unexpected way.
+    Internal,
+}
+
+impl ApiError {
+    /// The stable machine readable code identifying this error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest => "bad_request",
+            ApiError::InvalidUsername => "invalid_username",
+            ApiError::InvalidPublicKey => "invalid_public_key",
+            ApiError::NotFound => "not_found",
+            ApiError::UserNotFound => "user_not_found",
+            ApiError::UserRenamed(_) => "user_renamed",
+            ApiError::PostNotFound => "post_not_found",
+            ApiError::AuthRequired => "auth_required",
+            ApiError::ProofRequired => "proof_required",
+            ApiError::AuthInvalid => "auth_invalid",
+            ApiError::AuthExpired => "auth_expired",
+            ApiError::UsernameTaken => "username_taken",
+            ApiError::Conflict => "conflict",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::WorkRequired => "pow_required",
+            ApiError::WorkInvalid => "pow_invalid",
+            ApiError::DbUnavailable => "db_unavailable",
+            ApiError::Database(_) => "db_error",
+            ApiError::Internal => "internal_error",
+        }
+    }
+
+    /// The HTTP status this error responds with.
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::BadRequest | ApiError::InvalidUsername | ApiError::InvalidPublicKey => {
+                Status::BadRequest
+            },
+            ApiError::NotFound | ApiError::UserNotFound | ApiError::PostNotFound => {
+                Status::NotFound
+            },
+            ApiError::UserRenamed(_) => Status::TemporaryRedirect,
+            ApiError::AuthRequired | ApiError::ProofRequired => Status::Unauthorized,
+            ApiError::AuthInvalid | ApiError::AuthExpired | ApiError::WorkInvalid => {
+                Status::Forbidden
+            },
+            ApiError::UsernameTaken | ApiError::Conflict => Status::Conflict,
+            ApiError::RateLimited(_) => Status::TooManyRequests,
+            ApiError::WorkRequired => Status::PreconditionRequired,
+            ApiError::DbUnavailable => Status::ServiceUnavailable,
+            ApiError::Database(_) | ApiError::Internal => Status::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ApiError::BadRequest => "The request was malformed.",
+            ApiError::InvalidUsername => {
+                "Usernames must be between 1 and 100 letters, digits, '.', '-' or '_'."
+            },
+            ApiError::InvalidPublicKey => {
+                "The public key must be the base64 encoding of a 32 byte public key."
+            },
+            ApiError::NotFound => "The requested resource does not exist.",
+            ApiError::UserNotFound => "No user exists with that username.",
+            ApiError::UserRenamed(_) => {
+                "The user has been renamed, and is found at the location given."
+            },
+            ApiError::PostNotFound => "No post exists with that ID.",
+            ApiError::AuthRequired => "A proof of identity or session is required.",
+            ApiError::ProofRequired => {
+                "A proof of identity is required, a session is not accepted for this request."
+            },
+            ApiError::AuthInvalid => "The proof of identity is not valid.",
+            ApiError::AuthExpired => {
+                "The proof of identity has expired, a new authentication token must be requested."
+            },
+            ApiError::UsernameTaken => "A user with that username already exists.",
+            ApiError::Conflict => "The request conflicts with existing data.",
+            ApiError::RateLimited(_) => "Too many requests have been made, try again later.",
+            ApiError::WorkRequired => {
+                "A proof of work is required, solve a challenge from the `pow` endpoint."
+            },
+            ApiError::WorkInvalid => {
+                "The proof of work is not valid, has expired, or has already been used."
+            },
+            ApiError::DbUnavailable => "The database is unavailable.",
+            ApiError::Database(_) => "A database error occurred.",
+            ApiError::Internal => "An internal server error occurred.",
+        })
+    }
+}
+
+/// Converts Diesel errors, mapping unique constraint violations to conflicts
+/// and missing rows to `NotFound`. Routes should map missing rows to a more
+/// specific error where one exists.
+impl From<DieselError> for ApiError {
+    fn from(error: DieselError) -> ApiError {
+        match error {
+            DieselError::NotFound => ApiError::NotFound,
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => ApiError::Conflict,
+            error => ApiError::Database(error),
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        // Details of database failures are logged rather than exposed.
+        if let ApiError::Database(ref error) = self {
+            log::error!("Database error: {}", error);
+        }
+        let body = ErrorResponse { error: self.code().to_string(), message: self.to_string() };
+        let mut response = status::Custom(self.status(), Json(body)).respond_to(request)?;
+        match self {
+            ApiError::UserRenamed(ref username) => {
+                response.set_raw_header("Location", renamed_location(request, username));
+            },
+            ApiError::RateLimited(retry_after) => {
+                response.set_raw_header("Retry-After", retry_after.to_string());
+            },
+            _ => {},
+        }
+        Ok(response)
+    }
+}
+
+/// Returns the URI of `request` with its `username` query parameter replaced by
+/// `username`.
+fn renamed_location(request: &Request, username: &str) -> String {
+    let uri = request.uri();
+    let query = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .map(|parameter| {
+            if parameter.starts_with("username=") {
+                format!("username={}", Uri::percent_encode(username))
+            } else {
+                parameter.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+/// Returns catchers which respond to Rocket's own errors, such as unmatched
+/// routes, malformed bodies and unavailable database connections, with the
+/// JSON form of `ApiError`.
+pub fn catchers() -> Vec<Catcher> {
+    catchers![
+        bad_request,
+        unauthorized,
+        not_found,
+        unprocessable_entity,
+        internal_error,
+        service_unavailable,
+    ]
+}
+
+/// Catches requests Rocket could not parse.
+#[catch(400)]
+fn bad_request() -> ApiError { ApiError::BadRequest }
+
+/// Catches requests which were refused for lacking proof of identity.
+#[catch(401)]
+fn unauthorized() -> ApiError { ApiError::AuthRequired }
+
+/// Catches requests which match no route.
+#[catch(404)]
+fn not_found() -> ApiError { ApiError::NotFound }
+
+/// Catches requests whose body could not be deserialized.
+#[catch(422)]
+fn unprocessable_entity() -> ApiError { ApiError::BadRequest }
+
+/// Catches routes which failed unexpectedly.
+#[catch(500)]
+fn internal_error() -> ApiError { ApiError::Internal }
+
+/// Catches requests for which no database connection could be acquired, which
+/// is the only way `CoreDbConn` fails.
+#[catch(503)]
+fn service_unavailable() -> ApiError { ApiError::DbUnavailable }
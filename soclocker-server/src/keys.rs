@@ -0,0 +1,264 @@
+//! This module contains the loading, generation and storage of the keypairs the
+//! server uses to seal authentication tokens.
+//!
+//! Keys are stored in a plain text key file holding one or more keypairs,
+//! separated by blank lines, of the form
+//!
+//! ```text
+//! ID: 2
+//! PK: <base64 public key>
+//! SK: <base64 secret key>
+//!
+//! ID: 1
+//! PK: <base64 public key>
+//! SK: <base64 secret key>
+//! EXPIRES: 2019-07-01 12:00:00
+//! ```
+//!
+//! The first keypair is the active key, used for all newly sealed tokens. The
+//! remaining keypairs are retired, and are published alongside the active key
+//! until their optional `EXPIRES` time (in UTC) has passed, so that clients
+//! holding tokens sealed before a rotation can still open them.
+//!
+//! A key file can be generated with `soclocker-server keygen <path>`, and a new
+//! active key added to it with `soclocker-server rotate <path>`. The server
+//! only loads its keys at launch, so must be restarted to seal tokens with a
+//! rotated key.
+
+use crate::TIMEOUT_SECONDS;
+use chrono::{Duration, NaiveDateTime, Utc};
+use rocket::{
+    config::Config,
+    fairing::{AdHoc, Fairing},
+};
+use sodiumoxide::crypto::box_ as pkc;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+/// The Rocket configuration key naming the file the server keypairs are loaded
+/// from. Can be overridden by the `ROCKET_SERVER_KEYS` environment variable.
+pub const KEY_FILE_CONFIG: &str = "server_keys";
+
+/// The Rocket configuration key containing the base64 encoded server secret
+/// key. Where present, this takes priority over `KEY_FILE_CONFIG`, and can be
+/// set through the `ROCKET_SERVER_SECRET_KEY` environment variable.
+pub const SECRET_KEY_CONFIG: &str = "server_secret_key";
+
+/// The Rocket configuration key containing the key ID of the key supplied by
+/// `SECRET_KEY_CONFIG`.
+pub const SECRET_KEY_ID_CONFIG: &str = "server_key_id";
+
+/// The key file used when neither configuration key is present.
+const DEFAULT_KEY_FILE: &str = "server_keys";
+
+/// The format `EXPIRES` times are written in within key files.
+const EXPIRES_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// A single server keypair, identified by its key ID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerKeypair {
+    /// The ID clients use to select this key.
+    pub id: u32,
+
+    /// The public key, published to clients.
+    pub public: pkc::PublicKey,
+
+    /// The secret key, used to seal tokens.
+    pub secret: pkc::SecretKey,
+
+    /// The time after which a retired key is no longer published.
+    pub expires: Option<NaiveDateTime>,
+}
+
+/// The set of keypairs held by the server: one active keypair, and any number
+/// of retired keypairs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Keyring {
+    /// The keypair used to seal new tokens.
+    pub active: ServerKeypair,
+
+    /// Previously active keypairs, newest first.
+    pub retired: Vec<ServerKeypair>,
+}
+
+impl Keyring {
+    /// Constructs a keyring containing only the supplied keypair.
+    pub fn new(active: ServerKeypair) -> Keyring { Keyring { active, retired: vec![] } }
+
+    /// Returns the active keypair followed by every retired keypair which has
+    /// not yet expired at `now`.
+    pub fn valid_at(&self, now: NaiveDateTime) -> impl Iterator<Item = &ServerKeypair> {
+        Some(&self.active).into_iter().chain(
+            self.retired.iter().filter(move |keypair| keypair.expires.map_or(true, |e| e > now)),
+        )
+    }
+
+    /// Replaces the active keypair with a newly generated one, retiring the
+    /// previous active keypair. The retired keypair expires once every token
+    /// it could have sealed has timed out.
+    pub fn rotate(&mut self) {
+        let id = self.retired.iter().map(|keypair| keypair.id).fold(self.active.id, u32::max) + 1;
+        let mut previous = std::mem::replace(&mut self.active, generate(id));
+        previous.expires = Some(Utc::now().naive_utc() + Duration::seconds(TIMEOUT_SECONDS));
+        self.retired.insert(0, previous);
+    }
+}
+
+/// Marker type used to construct the fairing which loads the server keyring
+/// into managed state.
+#[derive(Debug)]
+pub struct ServerKeys;
+
+impl ServerKeys {
+    /// Constructs a fairing which loads the server keyring from the Rocket
+    /// configuration on attach, and manages it for use by the routes. Launch
+    /// is aborted if the keys cannot be loaded.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::on_attach("Server Keys", |rocket| {
+            match from_config(rocket.config()) {
+                Ok(keyring) => Ok(rocket.manage(keyring)),
+                Err(e) => {
+                    rocket::logger::error("Failed to load the server keys.");
+                    rocket::logger::error_(&e.to_string());
+                    Err(rocket)
+                },
+            }
+        })
+    }
+}
+
+/// Loads the server keyring as specified by the supplied configuration, either
+/// as a single key directly from `SECRET_KEY_CONFIG` or from the file named by
+/// `KEY_FILE_CONFIG`.
+pub fn from_config(config: &Config) -> io::Result<Keyring> {
+    if let Ok(secret) = config.get_str(SECRET_KEY_CONFIG) {
+        let secret = decode_secret(secret)?;
+        let public = secret.public_key();
+        let id = config.get_int(SECRET_KEY_ID_CONFIG).unwrap_or(1) as u32;
+        return Ok(Keyring::new(ServerKeypair { id, public, secret, expires: None }));
+    }
+    let path = config.get_str(KEY_FILE_CONFIG).unwrap_or(DEFAULT_KEY_FILE);
+    load(&config.root_relative(path))
+}
+
+/// Generates a new server keypair with the given key ID.
+pub fn generate(id: u32) -> ServerKeypair {
+    let (public, secret) = pkc::gen_keypair();
+    ServerKeypair { id, public, secret, expires: None }
+}
+
+/// Reads a server keyring from the key file at `path`, checking that each
+/// public key it contains belongs to its secret key.
+pub fn load(path: &Path) -> io::Result<Keyring> {
+    let contents = fs::read_to_string(path)?;
+    let mut keypairs = contents
+        .split("\n\n")
+        .filter(|block| !block.trim().is_empty())
+        .map(parse_keypair)
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter();
+    let active = keypairs.next().ok_or_else(|| invalid("key file contains no keys"))?;
+    let keyring = Keyring { active, retired: keypairs.collect() };
+    let mut ids = keyring.retired.iter().map(|keypair| keypair.id).collect::<Vec<_>>();
+    ids.push(keyring.active.id);
+    ids.sort();
+    ids.dedup();
+    if ids.len() != 1 + keyring.retired.len() {
+        return Err(invalid("key file contains duplicate key IDs"));
+    }
+    Ok(keyring)
+}
+
+/// Writes a server keyring to the key file at `path`, readable and writable
+/// only by its owner. If `replace` is false, fails rather than overwriting an
+/// existing file. Otherwise the file is replaced atomically, by writing a
+/// temporary file alongside it and renaming that into place. Any temporary file
+/// left by an earlier write which failed is overwritten.
+pub fn write(path: &Path, keyring: &Keyring, replace: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    let write_path = if replace {
+        options.write(true).create(true).truncate(true);
+        path.with_extension("new")
+    } else {
+        options.write(true).create_new(true);
+        path.to_path_buf()
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&write_path)?;
+    for (i, keypair) in Some(&keyring.active).into_iter().chain(&keyring.retired).enumerate() {
+        if i > 0 {
+            writeln!(file)?;
+        }
+        writeln!(file, "ID: {}", keypair.id)?;
+        writeln!(file, "PK: {}", base64::encode(&keypair.public.0))?;
+        writeln!(file, "SK: {}", base64::encode(&keypair.secret.0))?;
+        if let Some(expires) = keypair.expires {
+            writeln!(file, "EXPIRES: {}", expires.format(EXPIRES_FORMAT))?;
+        }
+    }
+    file.sync_all()?;
+    if replace {
+        fs::rename(&write_path, path)?;
+    }
+    Ok(())
+}
+
+/// Parses a single keypair entry from a key file.
+fn parse_keypair(block: &str) -> io::Result<ServerKeypair> {
+    let mut id = None;
+    let mut public = None;
+    let mut secret = None;
+    let mut expires = None;
+    for line in block.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(value) = line.strip_prefix("ID:") {
+            id = Some(value.trim().parse().map_err(|_| invalid("key ID is not a number"))?);
+        } else if let Some(value) = line.strip_prefix("PK:") {
+            public = Some(decode_public(value.trim())?);
+        } else if let Some(value) = line.strip_prefix("SK:") {
+            secret = Some(decode_secret(value.trim())?);
+        } else if let Some(value) = line.strip_prefix("EXPIRES:") {
+            expires = Some(
+                NaiveDateTime::parse_from_str(value.trim(), EXPIRES_FORMAT)
+                    .map_err(|_| invalid("key expiry is not a valid time"))?,
+            );
+        } else {
+            return Err(invalid("unrecognised line in key file"));
+        }
+    }
+    match (id, public, secret) {
+        (Some(id), Some(public), Some(secret)) => {
+            if secret.public_key() != public {
+                return Err(invalid("public key does not match secret key"));
+            }
+            Ok(ServerKeypair { id, public, secret, expires })
+        },
+        _ => Err(invalid("each key in the key file must have an ID, PK and SK entry")),
+    }
+}
+
+/// Decodes a base64 encoded public key.
+fn decode_public(value: &str) -> io::Result<pkc::PublicKey> {
+    base64::decode(value)
+        .ok()
+        .and_then(|bytes| pkc::PublicKey::from_slice(&bytes))
+        .ok_or_else(|| invalid("public key is not a valid base64 encoded key"))
+}
+
+/// Decodes a base64 encoded secret key.
+fn decode_secret(value: &str) -> io::Result<pkc::SecretKey> {
+    base64::decode(value)
+        .ok()
+        .and_then(|bytes| pkc::SecretKey::from_slice(&bytes))
+        .ok_or_else(|| invalid("secret key is not a valid base64 encoded key"))
+}
+
+/// Constructs an error for malformed key material. The message must never
+/// contain the key material itself.
+fn invalid(message: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, message) }
@@ -0,0 +1,119 @@
+//! This crate specifies a reference implementation of the back end of a social
+//! media service that has no server-side knowledge of its users authentication
+//! credentials.
+//!
+//! The server is assembled into a `Rocket` instance by `build_rocket`, which
+//! can be launched directly, embedded in another program, or driven by Rocket's
+//! local client in tests.
+#![feature(proc_macro_hygiene, decl_macro)]
+#![warn(missing_docs, missing_debug_implementations)]
+
+// Required due to poor support of 2018 edition by diesel.
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+
+pub mod challenge;
+pub mod database;
+pub mod error;
+pub mod keys;
+pub mod maintenance;
+pub mod models;
+pub mod pow;
+pub mod rate_limit;
+pub mod routes;
+pub mod schema;
+pub mod session;
+pub mod username;
+
+use crate::routes::*;
+use challenge::Challenges;
+use database::CoreDbConn;
+use keys::ServerKeys;
+use pow::Challenger;
+use rate_limit::RateLimiter;
+use rocket::{config::Config, routes, Rocket, Route};
+use rocket_contrib::serve::StaticFiles;
+use session::SessionKey;
+use std::path::PathBuf;
+
+/// The number of seconds after which a users authentication should timeout
+const TIMEOUT_SECONDS: i64 = 3600;
+
+/// Controls how the server is assembled by `build_rocket` and `ignite`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerOptions {
+    /// The base path the API routes are mounted under, `/_` by default. The
+    /// client expects the API at `/_`.
+    pub api_base: String,
+
+    /// A directory of static files, such as the built client, to serve from
+    /// `/`. No static files are served if this is `None`.
+    pub static_dir: Option<PathBuf>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions { ServerOptions { api_base: "/_".to_string(), static_dir: None } }
+}
+
+/// Builds the server from the supplied Rocket configuration, which must
+/// configure the `core_db` database and the server keys.
+pub fn build_rocket(config: Config, options: &ServerOptions) -> Rocket {
+    assemble(rocket::custom(config), options)
+}
+
+/// Builds the server from the Rocket configuration in `Rocket.toml` and the
+/// environment, as `rocket::ignite` does.
+pub fn ignite(options: &ServerOptions) -> Rocket { assemble(rocket::ignite(), options) }
+
+/// Returns every API route, for embedders mounting them themselves. The
+/// `ServerKeys`, `SessionKey`, `Challenges`, `RateLimiter`, `Challenger`,
+/// `FeedConfig`, `UsernameConfig` and `CoreDbConn` fairings must also be
+/// attached.
+pub fn routes() -> Vec<Route> {
+    routes![
+        server_public_key::get,
+        routes::pow::get,
+        auth::get,
+        auth::post,
+        user::get,
+        user::post,
+        user::confirm,
+        user::delete,
+        user::export,
+        user::put_key,
+        user::put_username,
+        post::post,
+        post::put,
+        post::rekey,
+        post::add_readers,
+        post::delete,
+        noa::get,
+        noa::delete,
+    ]
+}
+
+/// Attaches the server and session keys, the authentication challenge mode,
+/// the rate limiter, the proof of work challenger, the feed and username
+/// configuration, the database and its maintenance to `rocket`, registers the
+/// error catchers, and mounts the API routes and any static files.
+fn assemble(rocket: Rocket, options: &ServerOptions) -> Rocket {
+    let rocket = rocket
+        .attach(ServerKeys::fairing())
+        .attach(SessionKey::fairing())
+        .attach(Challenges::fairing())
+        .attach(RateLimiter::fairing())
+        .attach(Challenger::fairing())
+        .attach(noa::FeedConfig::fairing())
+        .attach(user::UsernameConfig::fairing())
+        .attach(CoreDbConn::fairing())
+        .attach(CoreDbConn::migrations_fairing())
+        .attach(maintenance::fairing())
+        .register(error::catchers())
+        .mount(&options.api_base, routes());
+    match options.static_dir {
+        Some(ref static_dir) => rocket.mount("/", StaticFiles::from(static_dir)),
+        None => rocket,
+    }
+}
@@ -1,56 +1,117 @@
-//! This program specifies a reference implementation of the back end of a
-//! social media service that has no server-side knowledge of its users
-//! authentication credentials.
-#![feature(proc_macro_hygiene, decl_macro)]
+//! The command line entry point of the SocLocker server, which launches the
+//! server built by `soclocker_server::ignite`, and provides subcommands for its
+//! administration.
 #![warn(missing_docs, missing_debug_implementations)]
 
-// Required due to poor support of 2018 edition by diesel.
-#[macro_use]
-extern crate diesel;
+use soclocker_server::{
+    database::{self, CoreDbConn},
+    keys::{self, Keyring},
+    maintenance,
+    ServerOptions,
+};
+use std::{env::args, path::Path, process};
 
-pub mod database;
-pub mod models;
-pub mod routes;
-pub mod schema;
+/// Initialises the program. Run as `soclocker-server keygen <path>` this writes
+/// a newly generated server key file to `<path>`, and run as
+/// `soclocker-server rotate <path>` it adds a new active key to the key file at
+/// `<path>`. Run as `soclocker-server migrate` it runs any pending database
+/// migrations, and as `soclocker-server maintenance` it purges expired state
+/// from the database once. Otherwise it launches the server, serving static
+/// files from the directory given as its first argument.
+///
+/// The server keys are loaded at launch by the `ServerKeys` fairing, as
+/// configured by the `server_keys` or `server_secret_key` Rocket configuration
+/// keys.
+fn main() {
+    sodiumoxide::init().unwrap();
+    let mut args = args().skip(1);
+    match args.next() {
+        Some(ref command) if command == "keygen" => keygen(args.next()),
+        Some(ref command) if command == "rotate" => rotate(args.next()),
+        Some(ref command) if command == "migrate" => migrate(),
+        Some(ref command) if command == "maintenance" => maintain(),
+        static_dir => launch(static_dir.unwrap_or("static".to_string())),
+    }
+}
 
-use crate::routes::*;
-use database::CoreDbConn;
-use rocket::routes;
-use rocket_contrib::serve::StaticFiles;
-use sodiumoxide::crypto::box_ as pkc;
-use std::env::args;
+/// Launches the server, serving static content from `static_dir`.
+fn launch(static_dir: String) {
+    let options = ServerOptions { static_dir: Some(static_dir.into()), ..Default::default() };
+    soclocker_server::ignite(&options).launch();
+}
 
-/// The number of seconds after which a users authentication should timeout
-const TIMEOUT_SECONDS: i64 = 3600;
+/// Runs any pending database migrations against the configured database.
+fn migrate() {
+    let rocket = rocket::ignite().attach(CoreDbConn::fairing());
+    if let Err(e) = database::migrate(&rocket) {
+        eprintln!("Could not run database migrations: {}", e);
+        process::exit(1);
+    }
+    println!("Database is up to date");
+}
 
-/// Initialises the program, including loading the private and public keys which
-/// are used by the server from an included file, an example for which can be
-/// seen in `server_keys.example`.
-fn main() {
-    sodiumoxide::init().unwrap();
-    let (server_public, server_secret) = include!("server_keys");
-    println!(
-        "Starting Server With: {:?} / {:?}",
-        base64::encode(&server_secret.0),
-        &server_secret.0
-    );
-    rocket::ignite()
-        .manage(server_public)
-        .manage(server_secret)
-        .attach(CoreDbConn::fairing())
-        .mount(
-            "/_",
-            routes![
-                server_public_key::get,
-                auth::get,
-                auth::post,
-                user::get,
-                user::post,
-                post::post,
-                post::put,
-                noa::get,
-            ],
-        )
-        .mount("/", StaticFiles::from(args().skip(1).next().unwrap_or("static".to_string())))
-        .launch();
+/// Purges expired state from the configured database, as the server does
+/// periodically while running.
+fn maintain() {
+    let rocket = rocket::ignite().attach(CoreDbConn::fairing());
+    let result = match CoreDbConn::get_one(&rocket) {
+        Some(conn) => maintenance::run(&conn.0).map_err(|e| e.to_string()),
+        None => Err("no database connection is available".to_string()),
+    };
+    match result {
+        Ok(counts) => println!("Purged {}", counts),
+        Err(e) => {
+            eprintln!("Could not run maintenance: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+/// Generates a new server key file at `path`, printing only the public key.
+fn keygen(path: Option<String>) {
+    let path = key_file_argument("keygen", path);
+    let keyring = Keyring::new(keys::generate(1));
+    if let Err(e) = keys::write(Path::new(&path), &keyring, false) {
+        eprintln!("Could not write server keys to {}: {}", path, e);
+        process::exit(1);
+    }
+    println!("Wrote server keys to {}", path);
+    print_active(&keyring);
+}
+
+/// Replaces the active key in the key file at `path` with a newly generated
+/// one, retiring the previous active key. A running server continues to use the
+/// keys it loaded at launch until it is restarted.
+fn rotate(path: Option<String>) {
+    let path = key_file_argument("rotate", path);
+    let result = keys::load(Path::new(&path)).and_then(|mut keyring| {
+        keyring.rotate();
+        keys::write(Path::new(&path), &keyring, true).map(|_| keyring)
+    });
+    match result {
+        Ok(keyring) => {
+            println!("Rotated server keys in {}", path);
+            print_active(&keyring);
+            println!("Restart the server to use the new active key");
+        },
+        Err(e) => {
+            eprintln!("Could not rotate server keys in {}: {}", path, e);
+            process::exit(1);
+        },
+    }
+}
+
+/// Unwraps the key file argument of a subcommand, exiting with usage
+/// information if it is missing.
+fn key_file_argument(command: &str, path: Option<String>) -> String {
+    path.unwrap_or_else(|| {
+        eprintln!("usage: soclocker-server {} <path>", command);
+        process::exit(2);
+    })
+}
+
+/// Prints the ID and public key of the active key in `keyring`.
+fn print_active(keyring: &Keyring) {
+    println!("Active key ID: {}", keyring.active.id);
+    println!("Public key: {}", base64::encode(&keyring.active.public.0));
 }
@@ -0,0 +1,132 @@
+//! This module contains the periodic maintenance of the database, which purges
+//! time-limited state once it has expired.
+//!
+//! Expired authentication tokens are otherwise only replaced when their user
+//! next requests one, and abandoned registrations and lapsed username
+//! reservations would never be removed. Maintenance runs in a background
+//! thread of the server every `maintenance_interval_minutes`, 10 by default, or
+//! never if it is set to `0`, in which case `soclocker-server maintenance`
+//! should be run periodically instead.
+
+use crate::{
+    database::Connection,
+    schema::{
+        Auth::dsl::{Auth, Timeout as AuthTimeout},
+        PendingRegistrations::dsl::{PendingRegistrations, Timeout as PendingTimeout},
+        UsernameReservations::dsl::{Expires, UsernameReservations},
+    },
+};
+use chrono::{NaiveDateTime, Utc};
+use diesel::{Connection as _, ExpressionMethods, QueryDsl, QueryResult, RunQueryDsl};
+use rocket::fairing::{AdHoc, Fairing};
+use rocket_contrib::databases::database_config;
+use std::{fmt, thread, time::Duration};
+
+/// The Rocket configuration key containing the number of minutes between each
+/// run of the maintenance thread, where `0` disables it.
+pub const MAINTENANCE_INTERVAL_CONFIG: &str = "maintenance_interval_minutes";
+
+/// The number of minutes between runs when none is configured.
+const DEFAULT_INTERVAL_MINUTES: u64 = 10;
+
+/// The number of rows purged by a maintenance run from each table.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PurgeCounts {
+    /// The expired authentication tokens purged from `Auth`.
+    pub auth: usize,
+
+    /// The expired registrations purged from `PendingRegistrations`.
+    pub pending_registrations: usize,
+
+    /// The expired reservations purged from `UsernameReservations`.
+    pub username_reservations: usize,
+}
+
+impl fmt::Display for PurgeCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} authentication tokens, {} pending registrations and {} username reservations",
+            self.auth, self.pending_registrations, self.username_reservations
+        )
+    }
+}
+
+/// Deletes every row of time-limited state which has expired at `now`,
+/// returning the number deleted from each table.
+pub fn purge_expired(conn: &Connection, now: NaiveDateTime) -> QueryResult<PurgeCounts> {
+    // ```sql
+    // DELETE FROM Auth WHERE Timeout < {now};
+    // DELETE FROM PendingRegistrations WHERE Timeout < {now};
+    // DELETE FROM UsernameReservations WHERE Expires < {now};
+    // ```
+    Ok(PurgeCounts {
+        auth: diesel::delete(Auth.filter(AuthTimeout.lt(now))).execute(conn)?,
+        pending_registrations: diesel::delete(PendingRegistrations.filter(PendingTimeout.lt(now)))
+            .execute(conn)?,
+        username_reservations: diesel::delete(UsernameReservations.filter(Expires.lt(now)))
+            .execute(conn)?,
+    })
+}
+
+/// Runs maintenance against `conn`, logging the number of rows purged.
+pub fn run(conn: &Connection) -> QueryResult<PurgeCounts> {
+    let counts = purge_expired(conn, Utc::now().naive_utc())?;
+    log::info!("Maintenance purged {}", counts);
+    Ok(counts)
+}
+
+/// Constructs a fairing which loads the maintenance interval from the Rocket
+/// configuration on attach, and starts the maintenance thread on launch. The
+/// thread opens its own connection to the `core_db` database for each run.
+/// Launch is aborted if the interval is not valid.
+pub fn fairing() -> impl Fairing {
+    AdHoc::on_attach("Maintenance", |rocket| {
+        let minutes = match rocket.config().get_int(MAINTENANCE_INTERVAL_CONFIG) {
+            Ok(minutes) if minutes >= 0 => minutes as u64,
+            Err(rocket::config::ConfigError::Missing(_)) => DEFAULT_INTERVAL_MINUTES,
+            _ => {
+                rocket::logger::error(&format!(
+                    "`{}` must be a non-negative integer.",
+                    MAINTENANCE_INTERVAL_CONFIG
+                ));
+                return Err(rocket);
+            },
+        };
+        if minutes == 0 {
+            return Ok(rocket);
+        }
+        let interval = Duration::from_secs(minutes * 60);
+        Ok(rocket.attach(AdHoc::on_launch("Maintenance Thread", move |rocket| {
+            let url = match database_config("core_db", rocket.config()) {
+                Ok(config) => config.url.to_string(),
+                Err(e) => {
+                    log::error!("Maintenance is disabled, the database is not configured: {:?}", e);
+                    return;
+                },
+            };
+            let spawned = thread::Builder::new()
+                .name("maintenance".to_string())
+                .spawn(move || maintain(&url, interval));
+            if let Err(e) = spawned {
+                log::error!("Could not start the maintenance thread: {}", e);
+            }
+        })))
+    })
+}
+
+/// Runs maintenance against the database at `url` every `interval`, starting
+/// immediately. Failures are logged, and retried at the next run.
+fn maintain(url: &str, interval: Duration) {
+    loop {
+        match Connection::establish(url) {
+            Ok(conn) => {
+                if let Err(e) = run(&conn) {
+                    log::error!("Maintenance failed: {}", e);
+                }
+            },
+            Err(e) => log::error!("Maintenance could not connect to the database: {}", e),
+        }
+        thread::sleep(interval);
+    }
+}
@@ -1,7 +1,7 @@
 //! This module contains all the local structure definitions of models used by
 //! the database and API.
 
-use crate::schema::{Auth, Posts, Users, NOA};
+use crate::schema::{Auth, PendingRegistrations, Posts, UsernameReservations, Users, NOA};
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 
@@ -19,21 +19,203 @@ pub struct User {
     pub username: String,
 }
 
-/// Used to receive posts to the `user` endpoint and insert them into the
-/// database.
-#[derive(
-    Debug, PartialEq, Eq, Clone, Hash, Default, Deserialize, Queryable, Serialize, Insertable,
-)]
-#[table_name = "Users"]
-pub struct UserInsert<'a, 'b> {
+/// Response given when a request fails, as produced by `ApiError`
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    /// The machine readable code identifying the error.
+    pub error: String,
+
+    /// A human readable description of the error.
+    pub message: String,
+}
+
+/// Represents everything the server holds about a user, as returned by the
+/// `user/export` endpoint
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
+pub struct UserExport {
+    /// The user's record.
+    pub user: User,
+
+    /// The time the archive was produced.
+    #[serde(rename = "exportedAt")]
+    pub exported_at: NaiveDateTime,
+
+    /// Every post the user authored or was granted access to, newest first.
+    pub posts: Vec<PostResponse>,
+
+    /// Every NOA granting access to a post the user authored, or granting the
+    /// user access to a post.
+    pub noas: Vec<NoaExport>,
+
+    /// The keys of the usernames the user has renamed from which are still
+    /// reserved for them.
+    #[serde(rename = "reservedUsernames")]
+    pub reserved_usernames: Vec<String>,
+}
+
+/// Represents a single NOA within a `UserExport`
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Queryable, Serialize)]
+pub struct NoaExport {
+    /// The ID of the post access is granted to.
+    #[serde(rename = "postId")]
+    pub post_id: i32,
+
+    /// The username of the reader granted access.
+    pub username: String,
+
+    /// The post's secret key, encrypted for the reader by the author.
+    #[serde(rename = "encryptedSecretKey")]
+    pub encrypted_secret_key: String,
+
+    /// The nonce used to encrypt the secret key.
+    pub nonce: String,
+}
+
+/// Used to receive a new username for a user, as sent to the `user/username`
+/// endpoint.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
+pub struct UsernameData {
+    /// The current username of the user.
+    pub username: String,
+
+    /// The proof of identity of the user, which may be omitted when a session
+    /// is supplied instead.
+    #[serde(default)]
+    pub proof: Option<String>,
+
+    /// The username the user wishes to be known by.
+    #[serde(rename = "newUsername")]
+    pub new_username: String,
+}
+
+/// Used to insert the reservation of a username a user has renamed from.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Insertable)]
+#[table_name = "UsernameReservations"]
+pub struct UsernameReservationInsert<'a> {
+    /// The key of the username being reserved, as given by `username::key`.
+    #[column_name = "Username"]
+    pub username: &'a str,
+
+    /// The ID of the user it is reserved for.
+    #[column_name = "UserID"]
+    pub user_id: i32,
+
+    /// The time the reservation ends.
+    #[column_name = "Expires"]
+    pub expires: NaiveDateTime,
+}
+
+/// Used to receive a new public key for a user, as sent to the `user/key`
+/// endpoint, along with everything encrypted under their previous key
+/// re-encrypted under the new one.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
+pub struct UserKeyData {
+    /// The username of the user whose key is being replaced.
+    pub username: String,
+
+    /// The proof of identity of the user, made with their previous key. This is
+    /// required, as sessions are not accepted.
+    #[serde(default)]
+    pub proof: Option<String>,
+
+    /// The new public key of the user.
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+
+    /// Every post authored by the user, re-encrypted under the new key.
+    pub posts: Vec<PostKeyTarget>,
+
+    /// Every NOA on a post the user authored, and every NOA granting the user
+    /// access, with the post's secret key re-encrypted under the new key.
+    pub noas: Vec<NoaExport>,
+}
+
+/// Represents a single post within `UserKeyData`
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
+pub struct PostKeyTarget {
+    /// The ID of the post.
+    #[serde(rename = "postId")]
+    pub post_id: i32,
+
+    /// The content of the post, encrypted under the author's new key.
+    pub content: String,
+
+    /// The nonce used to encrypt the content.
+    pub nonce: String,
+
+    /// The post's public key, encrypted under the author's new key.
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+
+    /// The nonce used to encrypt the post's public key.
+    #[serde(rename = "publicKeyNonce")]
+    pub public_key_nonce: String,
+}
+
+/// Used to receive posts to the `user` endpoint.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Deserialize, Serialize)]
+pub struct UserData {
     /// The public key the user wishes to register.
-    #[column_name = "PublicKey"]
     #[serde(rename = "publicKey")]
+    pub public_key: String,
+
+    /// The username the user wishes to alias to their public key.
+    pub username: String,
+}
+
+/// Used to receive the decrypted registration token, as sent to the
+/// `user/confirm` endpoint.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
+pub struct UserConfirm {
+    /// The username being registered.
+    pub username: String,
+
+    /// The registration token, decrypted by the secret key of the public key
+    /// being registered.
+    #[serde(rename = "decryptedToken")]
+    pub decrypted_token: String,
+}
+
+/// Used to insert registrations awaiting confirmation into the database.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Insertable)]
+#[table_name = "PendingRegistrations"]
+pub struct PendingRegistrationInsert<'a, 'b, 'c, 'd> {
+    /// The public key being registered.
+    #[column_name = "PublicKey"]
+    pub public_key: &'a str,
+
+    /// The normalised username being registered.
+    #[column_name = "Username"]
+    pub username: &'b str,
+
+    /// The key of the username, as given by `username::key`.
+    #[column_name = "UsernameKey"]
+    pub username_key: &'c str,
+
+    /// The token which must be returned decrypted to confirm the registration.
+    #[column_name = "ExpectedToken"]
+    pub expected_token: &'d str,
+
+    /// The time the registration must be confirmed by.
+    #[column_name = "Timeout"]
+    pub timeout: NaiveDateTime,
+}
+
+/// Used to insert new users into the database.
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Insertable)]
+#[table_name = "Users"]
+pub struct UserInsert<'a, 'b, 'c> {
+    /// The public key the user is registering.
+    #[column_name = "PublicKey"]
     pub public_key: &'a str,
 
-    /// The username the user wishes to alias to their
+    /// The normalised username the user is registering.
     #[column_name = "Username"]
     pub username: &'b str,
+
+    /// The key of the username, as given by `username::key`.
+    #[column_name = "UsernameKey"]
+    pub username_key: &'c str,
 }
 
 /// Used to insert Auth records to insert them into the database.
@@ -44,6 +226,10 @@ pub struct AuthInsert<'a, 'b> {
     #[column_name = "PublicKey"]
     pub public_key: &'a str,
 
+    /// The name of the action the authentication authorises
+    #[column_name = "Action"]
+    pub action: &'a str,
+
     /// The token expected to be provided by the client for valid authentication
     #[column_name = "ExpectedToken"]
     pub expected_token: &'b str,
@@ -63,6 +249,48 @@ pub struct AuthResponse {
 
     /// The decryption nonce for the `encrypted_token`.
     pub nonce: String,
+
+    /// The ID of the server key the `encrypted_token` was sealed with.
+    #[serde(rename = "keyId")]
+    pub key_id: u32,
+}
+
+/// Response given to the user when they query for the server public keys
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Deserialize, Serialize)]
+pub struct ServerPublicKeysResponse {
+    /// The ID of the key used to seal new tokens.
+    #[serde(rename = "activeKeyId")]
+    pub active_key_id: u32,
+
+    /// The active key followed by every retired key which is still valid.
+    pub keys: Vec<ServerPublicKey>,
+}
+
+/// Represents a single published server public key
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Deserialize, Serialize)]
+pub struct ServerPublicKey {
+    /// The ID of the key.
+    pub id: u32,
+
+    /// The public key.
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+
+    /// The time after which a retired key will no longer be published.
+    pub expires: Option<NaiveDateTime>,
+}
+
+/// Response given to the user when they request a proof of work challenge
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Deserialize, Serialize)]
+pub struct PowChallenge {
+    /// The salt to search for a nonce for.
+    pub salt: String,
+
+    /// The number of zero bits the digest of the solution must begin with.
+    pub difficulty: u8,
+
+    /// The time after which a solution will no longer be accepted.
+    pub expires: NaiveDateTime,
 }
 
 /// Format expected on authentication to validate the user and confirm their
@@ -75,6 +303,31 @@ pub struct AuthValidate<'a> {
 
     /// The username the user is attempting to authenticate as.
     pub username: String,
+
+    /// Whether a session token should be issued if the validation succeeds.
+    #[serde(default)]
+    pub session: bool,
+}
+
+/// Response given to the user when they validate an authentication token
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum AuthValidateResponse {
+    /// The validation succeeded, and no session was requested.
+    Valid(bool),
+
+    /// The validation succeeded, and a session was issued.
+    Session(SessionResponse),
+}
+
+/// Represents a session issued to a user
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Deserialize, Serialize)]
+pub struct SessionResponse {
+    /// The session token, to be sent as `Authorization: Bearer <session>`.
+    pub session: String,
+
+    /// The time the session expires.
+    pub expires: NaiveDateTime,
 }
 
 /// Used to insert new posts into the database.
@@ -153,7 +406,7 @@ pub struct Post {
 
     /// The nonce used to encrypt the public key
     #[serde(rename = "publicKeyNonce")]
-    pub public_key_nonce: String
+    pub public_key_nonce: String,
 }
 
 /// Used to insert new posts into the database
@@ -168,15 +421,16 @@ pub struct PostData {
     /// The username of the user who created the post
     pub username: String,
 
-    /// The authentication token for proof of identity
-    pub proof: String,
+    /// The authentication token for proof of identity, if no session is used
+    #[serde(default)]
+    pub proof: Option<String>,
 
     /// The encrypted public key used to encode the content
     #[serde(rename = "publicKey")]
     pub public_key: String,
 
     /// The nonce used to encrypt the public key which encoded the content
-    #[serde(rename="publicKeyNonce")]
+    #[serde(rename = "publicKeyNonce")]
     pub public_key_nonce: String,
 
     /// The user id and corresponding encrypted secret keys, as well as the
@@ -185,6 +439,66 @@ pub struct PostData {
     pub noa_encrypted_keys: Vec<PostNOATarget>,
 }
 
+/// Response given when a post is created or re-keyed, or readers are added
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Default, Deserialize, Serialize)]
+pub struct PostCreatedResponse {
+    /// The ID of the created post.
+    #[serde(rename = "postId")]
+    pub post_id: i32,
+
+    /// The username of each NOA target which could not be granted access to
+    /// the post, because no such user exists or they already have access.
+    #[serde(rename = "failedReaders")]
+    pub failed_readers: Vec<String>,
+}
+
+/// Represents the information needed to re-key a post
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
+pub struct PostRekeyData {
+    /// The ID of the post to re-key
+    #[serde(rename = "postId")]
+    pub post_id: i32,
+
+    /// The proof of authentication, if no session is used
+    #[serde(default)]
+    pub proof: Option<String>,
+
+    /// The content of the post, encrypted with the new key
+    pub content: String,
+
+    /// The nonce used to encrypt the content
+    pub nonce: String,
+
+    /// The new encrypted public key used to encode the content
+    #[serde(rename = "publicKey")]
+    pub public_key: String,
+
+    /// The nonce used to encrypt the new public key
+    #[serde(rename = "publicKeyNonce")]
+    pub public_key_nonce: String,
+
+    /// The readers of the new version, with the new secret key encrypted for
+    /// each of them. Every other reader loses access.
+    #[serde(rename = "noaEncryptedKeys")]
+    pub noa_encrypted_keys: Vec<PostNOATarget>,
+}
+
+/// Represents the information needed to grant more readers access to a post
+#[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Serialize)]
+pub struct PostReadersData {
+    /// The ID of the post to grant access to
+    #[serde(rename = "postId")]
+    pub post_id: i32,
+
+    /// The proof of authentication, if no session is used
+    #[serde(default)]
+    pub proof: Option<String>,
+
+    /// The new readers, with the post's secret key encrypted for each of them.
+    #[serde(rename = "noaEncryptedKeys")]
+    pub noa_encrypted_keys: Vec<PostNOATarget>,
+}
+
 /// Represents a single NOA target for use within PostData
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Queryable, Serialize)]
 pub struct PostNOATarget {
@@ -249,14 +563,18 @@ pub struct NoaResponse {
     /// Each username permitted to see the post.
     #[serde(rename = "allReaders")]
     pub all_readers: Vec<String>,
-
 }
 
 /// Represents a response from the NOA endpoint
 #[derive(Debug, PartialEq, Eq, Clone, Hash, Deserialize, Queryable, Serialize)]
 pub struct NoaOuterResponse {
+    /// The posts on this page of the feed, newest first.
     pub noas: Vec<NoaResponse>,
-    pub pages: i64,
+
+    /// The cursor requesting the next page of the feed, if there are older
+    /// posts.
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
 }
 
 /// Represents the information needed to edit a post
@@ -266,8 +584,9 @@ pub struct PostPutData {
     #[serde(rename = "postId")]
     pub post_id: i32,
 
-    /// The proof of authentication
-    pub proof: String,
+    /// The proof of authentication, if no session is used
+    #[serde(default)]
+    pub proof: Option<String>,
 
     /// The new encrypted content for the post
     #[serde(rename = "newContent")]
@@ -276,4 +595,4 @@ pub struct PostPutData {
     /// The new nonce used to encrypt the content
     #[serde(rename = "newNonce")]
     pub new_nonce: String,
-}
\ No newline at end of file
+}
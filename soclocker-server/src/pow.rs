@@ -0,0 +1,313 @@
+//! This module contains the optional hashcash style proof of work required
+//! before issuing authentication tokens and registering users, which makes
+//! scripting either in bulk expensive.
+//!
+//! A client fetches a challenge from the `pow` endpoint, giving a salt and a
+//! difficulty, and searches for a nonce such that the SHA-512 digest of
+//! `<salt>:<nonce>` begins with at least that many zero bits. It then sends
+//! `X-Proof-Of-Work: <salt>:<nonce>` with the request. Salts are authenticated
+//! by the server rather than stored, and each may be spent once before it
+//! expires. Servers sharing a proof of work key, as set by the `pow_key`
+//! configuration key, accept each other's salts.
+//!
+//! The difficulty rises by one bit, doubling the expected work, each time the
+//! number of proofs accepted in the last minute doubles past the configured
+//! `load_step`. Proof of work is disabled unless enabled in the
+//! `proof_of_work` table of `Rocket.toml`, such as
+//!
+//! ```toml
+//! [global.proof_of_work]
+//! enabled = true
+//! difficulty = 16
+//! max_difficulty = 24
+//! load_step = 30
+//! ```
+
+use crate::{error::ApiError, models::PowChallenge};
+use chrono::{Duration, NaiveDateTime, Utc};
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    http::Status,
+    request::{self, FromRequest},
+    Outcome,
+    Request,
+    State,
+};
+use sodiumoxide::{
+    crypto::{auth, hash::sha512},
+    randombytes::randombytes,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    sync::Mutex,
+    time::{Duration as StdDuration, Instant},
+};
+
+/// The Rocket configuration key containing the proof of work settings.
+pub const PROOF_OF_WORK_CONFIG: &str = "proof_of_work";
+
+/// The Rocket configuration key containing the base64 encoded key challenge
+/// salts are authenticated with. This should be set when several servers are
+/// behind a load balancer, so that each accepts the salts issued by the others.
+/// Otherwise a new key is generated at launch, invalidating every outstanding
+/// challenge.
+pub const POW_KEY_CONFIG: &str = "pow_key";
+
+/// The header a solved challenge is sent in.
+pub const PROOF_OF_WORK_HEADER: &str = "X-Proof-Of-Work";
+
+/// The number of seconds a challenge may be solved within.
+const CHALLENGE_SECONDS: i64 = 300;
+
+/// The number of random bytes in each salt, making each unique.
+const RANDOM_BYTES: usize = 16;
+
+/// The length of a decoded salt: the 8 byte expiry time, the 1 byte
+/// difficulty, the random bytes and the authentication tag.
+const SALT_BYTES: usize = 8 + 1 + RANDOM_BYTES + auth::TAGBYTES;
+
+/// The greatest difficulty which may be configured, beyond which challenges
+/// could not be solved in reasonable time.
+const MAX_DIFFICULTY: u8 = 32;
+
+/// The longest nonce accepted, bounding the work of checking a solution.
+const MAX_NONCE_LENGTH: usize = 64;
+
+/// The number of spent salts beyond which those which have expired are
+/// discarded.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The proof of work settings loaded from the Rocket configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowConfig {
+    /// Whether requests must include a proof of work.
+    pub enabled: bool,
+
+    /// The difficulty of challenges when the server is not under load.
+    pub difficulty: u8,
+
+    /// The greatest difficulty challenges rise to under load.
+    pub max_difficulty: u8,
+
+    /// The number of proofs accepted per minute beyond which the difficulty
+    /// begins to rise.
+    pub load_step: u32,
+}
+
+impl Default for PowConfig {
+    fn default() -> PowConfig {
+        PowConfig { enabled: false, difficulty: 16, max_difficulty: 24, load_step: 30 }
+    }
+}
+
+/// Issues and checks proof of work challenges.
+#[derive(Debug)]
+pub struct Challenger {
+    config: PowConfig,
+
+    /// The key challenge salts are authenticated with.
+    key: auth::Key,
+
+    /// The times of the proofs accepted in the last minute, oldest first.
+    accepted: Mutex<VecDeque<Instant>>,
+
+    /// The salts which have been spent, with the times they expire.
+    spent: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl Challenger {
+    /// Constructs a fairing which loads the proof of work settings and key from
+    /// the Rocket configuration on attach, generating a key if none is
+    /// configured, and manages a challenger for use by the routes. Launch is
+    /// aborted if either is not valid.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::on_attach("Proof of Work", |rocket| {
+            let config = match rocket.config().get_table(PROOF_OF_WORK_CONFIG) {
+                Ok(table) => {
+                    let default = PowConfig::default();
+                    let int = |key: &str, default: i64| {
+                        match table.get(key) {
+                            Some(value) => value.as_integer(),
+                            None => Some(default),
+                        }
+                    };
+                    let enabled = match table.get("enabled") {
+                        Some(value) => value.as_bool(),
+                        None => Some(default.enabled),
+                    };
+                    let difficulty = int("difficulty", default.difficulty.into())
+                        .and_then(|difficulty| difficulty.try_into().ok());
+                    let max_difficulty = int("max_difficulty", default.max_difficulty.into())
+                        .and_then(|difficulty| difficulty.try_into().ok());
+                    let load_step = int("load_step", default.load_step.into())
+                        .and_then(|load_step| load_step.try_into().ok());
+                    match (enabled, difficulty, max_difficulty, load_step) {
+                        (
+                            Some(enabled),
+                            Some(difficulty),
+                            Some(max_difficulty),
+                            Some(load_step),
+                        ) if difficulty <= max_difficulty
+                            && max_difficulty <= MAX_DIFFICULTY
+                            && load_step > 0 =>
+                        {
+                            Some(PowConfig { enabled, difficulty, max_difficulty, load_step })
+                        },
+                        _ => None,
+                    }
+                },
+                Err(_) => Some(PowConfig::default()),
+            };
+            let key = match rocket.config().get_str(POW_KEY_CONFIG) {
+                Ok(key) => base64::decode(key).ok().and_then(|key| auth::Key::from_slice(&key)),
+                Err(_) => Some(auth::gen_key()),
+            };
+            match (config, key) {
+                (Some(config), Some(key)) => {
+                    Ok(rocket.manage(Challenger {
+                        config,
+                        key,
+                        accepted: Mutex::new(VecDeque::new()),
+                        spent: Mutex::new(HashMap::new()),
+                    }))
+                },
+                (None, _) => {
+                    rocket::logger::error(
+                        "The proof of work difficulties must be at most 32 and in order, and the \
+                         load step positive.",
+                    );
+                    Err(rocket)
+                },
+                (_, None) => {
+                    rocket::logger::error(
+                        "The proof of work key is not a valid base64 encoded key.",
+                    );
+                    Err(rocket)
+                },
+            }
+        })
+    }
+
+    /// Issues a new challenge at the current difficulty, or of no difficulty
+    /// if proof of work is disabled.
+    pub fn challenge(&self, now: NaiveDateTime) -> PowChallenge {
+        let difficulty = if self.config.enabled { self.difficulty() } else { 0 };
+        let expires = now + Duration::seconds(CHALLENGE_SECONDS);
+        let mut salt = Vec::with_capacity(SALT_BYTES);
+        salt.extend_from_slice(&expires.timestamp().to_be_bytes());
+        salt.push(difficulty);
+        salt.extend_from_slice(&randombytes(RANDOM_BYTES));
+        let tag = auth::authenticate(&salt, &self.key);
+        salt.extend_from_slice(&tag.0);
+        PowChallenge {
+            salt: base64::encode_config(&salt, base64::URL_SAFE_NO_PAD),
+            difficulty,
+            expires,
+        }
+    }
+
+    /// Checks that `solution`, of the form `<salt>:<nonce>`, solves a challenge
+    /// issued by this server which has not expired at `now` or been spent, and
+    /// spends it.
+    pub fn verify(&self, solution: &str, now: NaiveDateTime) -> Result<(), ApiError> {
+        let separator = solution.rfind(':').ok_or(ApiError::WorkInvalid)?;
+        let (salt, nonce) = (&solution[..separator], &solution[separator + 1..]);
+        if nonce.len() > MAX_NONCE_LENGTH {
+            return Err(ApiError::WorkInvalid);
+        }
+        let salt = base64::decode_config(salt, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| ApiError::WorkInvalid)?;
+        if salt.len() != SALT_BYTES {
+            return Err(ApiError::WorkInvalid);
+        }
+        let (claims, tag) = salt.split_at(SALT_BYTES - auth::TAGBYTES);
+        let tag = auth::Tag::from_slice(tag).ok_or(ApiError::WorkInvalid)?;
+        if !auth::verify(&tag, claims, &self.key) {
+            return Err(ApiError::WorkInvalid);
+        }
+        let expires = i64::from_be_bytes(claims[..8].try_into().unwrap());
+        if expires < now.timestamp() {
+            return Err(ApiError::WorkInvalid);
+        }
+        let digest = sha512::hash(solution.as_bytes());
+        if leading_zero_bits(&digest.0) < u32::from(claims[8]) {
+            return Err(ApiError::WorkInvalid);
+        }
+
+        // Spent salts are kept until they expire, when they would be refused
+        // anyway.
+        let mut spent = self.spent.lock().unwrap_or_else(|e| e.into_inner());
+        if spent.len() > PRUNE_THRESHOLD {
+            spent.retain(|_, expires| *expires >= now.timestamp());
+        }
+        if spent.insert(salt, expires).is_some() {
+            return Err(ApiError::WorkInvalid);
+        }
+        drop(spent);
+
+        let now = Instant::now();
+        let mut accepted = self.accepted.lock().unwrap_or_else(|e| e.into_inner());
+        accepted.push_back(now);
+        Ok(())
+    }
+
+    /// The difficulty of challenges under the current load.
+    fn difficulty(&self) -> u8 {
+        let now = Instant::now();
+        let mut accepted = self.accepted.lock().unwrap_or_else(|e| e.into_inner());
+        while accepted.front().map_or(false, |&time| now - time > StdDuration::from_secs(60)) {
+            accepted.pop_front();
+        }
+        // One bit is added for each doubling of the load, in steps of
+        // `load_step` proofs per minute.
+        let steps = accepted.len() as u64 / u64::from(self.config.load_step);
+        let extra = 63 - (steps + 1).leading_zeros();
+        self.config.max_difficulty.min(self.config.difficulty.saturating_add(extra as u8))
+    }
+}
+
+/// Counts the zero bits at the start of `digest`.
+fn leading_zero_bits(digest: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in digest {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+/// A request which carried a valid proof of work in its `X-Proof-Of-Work`
+/// header, or any request if proof of work is disabled.
+///
+/// As a request guard this fails with `pow_required` if the header is missing,
+/// or `pow_invalid` if it is not valid, so routes should take a
+/// `Result<ProofOfWork, ApiError>` to respond with the error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProofOfWork;
+
+impl<'a, 'r> FromRequest<'a, 'r> for ProofOfWork {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ProofOfWork, ApiError> {
+        let challenger = match request.guard::<State<Challenger>>() {
+            Outcome::Success(challenger) => challenger,
+            _ => return Outcome::Failure((Status::InternalServerError, ApiError::Internal)),
+        };
+        if !challenger.config.enabled {
+            return Outcome::Success(ProofOfWork);
+        }
+        let solution = match request.headers().get_one(PROOF_OF_WORK_HEADER) {
+            Some(solution) => solution,
+            None => {
+                return Outcome::Failure((Status::PreconditionRequired, ApiError::WorkRequired))
+            },
+        };
+        match challenger.verify(solution.trim(), Utc::now().naive_utc()) {
+            Ok(()) => Outcome::Success(ProofOfWork),
+            Err(e) => Outcome::Failure((e.status(), e)),
+        }
+    }
+}
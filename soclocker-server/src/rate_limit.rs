@@ -0,0 +1,278 @@
+//! This module contains the rate limiting of routes which are expensive or
+//! which could be abused in bulk, such as requesting authentication tokens and
+//! registering users.
+//!
+//! Each limited route has a bucket per client IP address, charged when the
+//! request is received, and a bucket per username, charged by the route once
+//! it knows the username. Each bucket holds up to a minute's allowance of
+//! requests, and refills continuously. Buckets are held in memory, so are
+//! reset when the server restarts, and are not shared between servers.
+//!
+//! Limits are set per route in the `rate_limits` table of `Rocket.toml`,
+//! such as
+//!
+//! ```toml
+//! [global.rate_limits.auth]
+//! per_ip = 60
+//! per_username = 10
+//! ```
+//!
+//! where each is a number of requests per minute, and `0` disables the limit.
+//!
+//! Clients are identified by the address of the connection. When the server is
+//! behind a proxy, the proxy's addresses are listed in `trusted_proxies`, and
+//! connections from them are identified by their `X-Real-IP` header instead.
+
+use crate::{error::ApiError, username};
+use rocket::{
+    config::Value,
+    fairing::{AdHoc, Fairing},
+    http::Status,
+    request::{self, FromRequest},
+    Outcome,
+    Request,
+    State,
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    marker::PhantomData,
+    net::{IpAddr, Ipv4Addr},
+    sync::Mutex,
+    time::Instant,
+};
+
+/// The Rocket configuration key containing the table of limits for each route.
+pub const RATE_LIMITS_CONFIG: &str = "rate_limits";
+
+/// The Rocket configuration key containing the list of proxy addresses whose
+/// `X-Real-IP` header identifies the client.
+pub const TRUSTED_PROXIES_CONFIG: &str = "trusted_proxies";
+
+/// The number of buckets beyond which those which have refilled are discarded.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A route which is rate limited, as named in the `rate_limits` table.
+pub trait LimitedRoute {
+    /// The name of the route's table within `rate_limits`.
+    const NAME: &'static str;
+
+    /// The limits used when none are configured.
+    const DEFAULT: Limits;
+}
+
+/// The `auth` endpoint's GET route, which seals a new authentication token.
+///
+/// Its limit per username is disabled by default, as anyone may request tokens
+/// for any user, so would otherwise be able to prevent them logging in.
+#[derive(Debug)]
+pub enum AuthRoute {}
+
+impl LimitedRoute for AuthRoute {
+    const NAME: &'static str = "auth";
+    const DEFAULT: Limits = Limits { per_ip: 60, per_username: 0 };
+}
+
+/// The `user` endpoint's POST route, which begins a registration.
+#[derive(Debug)]
+pub enum RegisterRoute {}
+
+impl LimitedRoute for RegisterRoute {
+    const NAME: &'static str = "register";
+    const DEFAULT: Limits = Limits { per_ip: 10, per_username: 5 };
+}
+
+/// The number of requests per minute allowed to a route, where `0` is
+/// unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The requests allowed from a single IP address.
+    pub per_ip: u32,
+
+    /// The requests allowed concerning a single username.
+    pub per_username: u32,
+}
+
+/// What a bucket counts the requests of.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Ip(IpAddr),
+    Username(String),
+}
+
+/// The requests remaining to a single subject of a route.
+#[derive(Debug)]
+struct Bucket {
+    /// The requests remaining, which may be fractional while refilling.
+    tokens: f64,
+
+    /// The time `tokens` was last brought up to date.
+    updated: Instant,
+}
+
+/// The limits and buckets of every limited route.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: HashMap<&'static str, Limits>,
+    trusted_proxies: Vec<IpAddr>,
+    buckets: Mutex<HashMap<(&'static str, Subject), Bucket>>,
+}
+
+impl RateLimiter {
+    /// Constructs a fairing which loads the limits of each route from the
+    /// Rocket configuration on attach, and manages the rate limiter for use by
+    /// the routes. Launch is aborted if the limits or trusted proxies are not
+    /// valid.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::on_attach("Rate Limiter", |rocket| {
+            let mut limits = HashMap::new();
+            for &(name, default) in &[
+                (AuthRoute::NAME, AuthRoute::DEFAULT),
+                (RegisterRoute::NAME, RegisterRoute::DEFAULT),
+            ] {
+                let table = rocket
+                    .config()
+                    .get_table(RATE_LIMITS_CONFIG)
+                    .ok()
+                    .and_then(|routes| routes.get(name));
+                match load_limits(table, default) {
+                    Some(route_limits) => limits.insert(name, route_limits),
+                    None => {
+                        rocket::logger::error(&format!(
+                            "The rate limits of `{}` must be non-negative integers.",
+                            name
+                        ));
+                        return Err(rocket);
+                    },
+                };
+            }
+            let trusted_proxies = match rocket.config().get_slice(TRUSTED_PROXIES_CONFIG) {
+                Ok(proxies) => {
+                    proxies
+                        .iter()
+                        .map(|proxy| proxy.as_str().and_then(|proxy| proxy.parse().ok()))
+                        .collect::<Option<Vec<IpAddr>>>()
+                },
+                Err(_) => Some(Vec::new()),
+            };
+            let trusted_proxies = match trusted_proxies {
+                Some(trusted_proxies) => trusted_proxies,
+                None => {
+                    rocket::logger::error("The trusted proxies must be a list of IP addresses.");
+                    return Err(rocket);
+                },
+            };
+            Ok(rocket.manage(RateLimiter {
+                limits,
+                trusted_proxies,
+                buckets: Mutex::new(HashMap::new()),
+            }))
+        })
+    }
+
+    /// Charges a request to the bucket of `subject` for the route `name`,
+    /// failing with `RateLimited` if it is empty.
+    fn charge(&self, name: &'static str, subject: Subject) -> Result<(), ApiError> {
+        let per_minute = self.limit(name, &subject);
+        if per_minute == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let capacity = f64::from(per_minute);
+        let per_second = capacity / 60.0;
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        // Buckets which have refilled are equivalent to those never charged.
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|(name, subject), bucket| {
+                let capacity = f64::from(self.limit(name, subject));
+                let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * capacity / 60.0 < capacity
+            });
+        }
+        let bucket =
+            buckets.entry((name, subject)).or_insert(Bucket { tokens: capacity, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            let retry_after = ((1.0 - bucket.tokens) / per_second).ceil() as u64;
+            return Err(ApiError::RateLimited(retry_after));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// The IP address of the client making `request`, which is given by its
+    /// `X-Real-IP` header if the connection is from a trusted proxy. Requests
+    /// with no known address share a single bucket.
+    fn client_ip(&self, request: &Request) -> IpAddr {
+        match request.remote() {
+            Some(remote) if self.trusted_proxies.contains(&remote.ip()) => {
+                request.real_ip().unwrap_or_else(|| remote.ip())
+            },
+            Some(remote) => remote.ip(),
+            None => Ipv4Addr::UNSPECIFIED.into(),
+        }
+    }
+
+    /// The requests per minute allowed to `subject` of the route `name`.
+    fn limit(&self, name: &str, subject: &Subject) -> u32 {
+        let limits = self.limits[name];
+        match subject {
+            Subject::Ip(_) => limits.per_ip,
+            Subject::Username(_) => limits.per_username,
+        }
+    }
+}
+
+/// Loads the limits of a route from its configuration `table`, falling back to
+/// `default` for any which are not set. Returns `None` if any are not valid.
+fn load_limits(table: Option<&Value>, default: Limits) -> Option<Limits> {
+    let load = |key: &str, default: u32| {
+        match table.and_then(|table| table.get(key)) {
+            Some(value) => value.as_integer().and_then(|limit| u32::try_from(limit).ok()),
+            None => Some(default),
+        }
+    };
+    Some(Limits {
+        per_ip: load("per_ip", default.per_ip)?,
+        per_username: load("per_username", default.per_username)?,
+    })
+}
+
+/// A request to the limited route `R`, which has been charged to the bucket of
+/// the client's IP address.
+///
+/// As a request guard this fails with `rate_limited` if that bucket is empty,
+/// so routes should take a `Result<Throttle<R>, ApiError>` to respond with the
+/// `Retry-After` header. Clients are identified by the address of the
+/// connection, or by the `X-Real-IP` header of a trusted proxy.
+#[derive(Debug)]
+pub struct Throttle<'r, R: LimitedRoute> {
+    limiter: &'r RateLimiter,
+    route: PhantomData<R>,
+}
+
+impl<'r, R: LimitedRoute> Throttle<'r, R> {
+    /// Charges the request to the bucket of `username`, which is shared by
+    /// usernames differing only in case, failing with `rate_limited` if it is
+    /// empty.
+    pub fn charge_username(&self, username: &str) -> Result<(), ApiError> {
+        self.limiter.charge(R::NAME, Subject::Username(username::key(username)))
+    }
+}
+
+impl<'a, 'r, R: LimitedRoute> FromRequest<'a, 'r> for Throttle<'r, R> {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Throttle<'r, R>, ApiError> {
+        let limiter = match request.guard::<State<RateLimiter>>() {
+            Outcome::Success(limiter) => limiter.inner(),
+            _ => return Outcome::Failure((Status::InternalServerError, ApiError::Internal)),
+        };
+        match limiter.charge(R::NAME, Subject::Ip(limiter.client_ip(request))) {
+            Ok(()) => Outcome::Success(Throttle { limiter, route: PhantomData }),
+            Err(e) => Outcome::Failure((e.status(), e)),
+        }
+    }
+}
@@ -1,165 +1,458 @@
 //! Contains the routing control for the `auth` endpoint.
 
 use crate::{
+    challenge::{ChallengeMode, Challenges},
     database::CoreDbConn,
-    models::{AuthInsert, AuthResponse, AuthValidate},
+    error::ApiError,
+    keys::Keyring,
+    models::{AuthInsert, AuthResponse, AuthValidate, AuthValidateResponse, SessionResponse},
+    pow::ProofOfWork,
+    rate_limit::{AuthRoute, Throttle},
+    routes::user::user_not_found,
     schema::{
-        Auth::dsl::{PublicKey as AuthPublicKey, *},
+        Auth::dsl::{
+            Action as AuthAction,
+            Auth,
+            ExpectedToken,
+            PublicKey as AuthPublicKey,
+            Timeout,
+            ID as AuthID,
+        },
         Users::dsl::{PublicKey as UsersPublicKey, *},
     },
+    session::{Session, SessionKey},
+    username,
     TIMEOUT_SECONDS,
 };
 use chrono::{Duration, NaiveDateTime, Utc};
-use diesel::{dsl, ExpressionMethods, JoinOnDsl, NullableExpressionMethods, QueryDsl, RunQueryDsl};
+use diesel::{Connection, ExpressionMethods, OptionalExtension, QueryDsl, RunQueryDsl};
 use rand::{rngs::OsRng, Rng};
-use rocket::{get, http::Status, post, State};
+use rocket::{get, http::RawStr, post, request::FromFormValue, State};
 use rocket_contrib::json::Json;
-use sodiumoxide::crypto::box_ as pkc;
+use sodiumoxide::{crypto::box_ as pkc, utils::memcmp};
 
 /// Represents the size of data to use for randomly generating a validation key.
 /// The larger the value, the harder the bruteforce.
 type Validator = [u8; 32];
 
+/// The most authentication tokens a user may hold for a single action at once.
+/// Issuing another discards the oldest.
+const MAX_OUTSTANDING_TOKENS: usize = 8;
+
+/// The action an authentication token authorises, requested as the `action`
+/// parameter of the `auth` endpoint. A token is only accepted by the route it
+/// was requested for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Validating the token with a POST request to the `auth` endpoint, such as
+    /// to begin a session.
+    Login,
+
+    /// Creating a post, with a POST request to the `post` endpoint.
+    CreatePost,
+
+    /// Replacing a post, with a PUT request to the `post` endpoint.
+    EditPost,
+
+    /// Rekeying a post, with a PUT request to the `post/rekey` endpoint.
+    RekeyPost,
+
+    /// Adding readers to a post, with a POST request to the `post/readers`
+    /// endpoint.
+    AddReaders,
+
+    /// Deleting a post, with a DELETE request to the `post` endpoint.
+    DeletePost,
+
+    /// Reading the feed, with a GET request to the `noa` endpoint.
+    ReadFeed,
+
+    /// Hiding a post from the feed, with a DELETE request to the `noa`
+    /// endpoint.
+    HidePost,
+
+    /// Deleting the account, with a DELETE request to the `user` endpoint.
+    DeleteUser,
+
+    /// Exporting the account, with a GET request to the `user/export` endpoint.
+    ExportUser,
+
+    /// Rotating the account's key, with a PUT request to the `user/key`
+    /// endpoint.
+    RotateKey,
+
+    /// Changing the account's username, with a PUT request to the
+    /// `user/username` endpoint.
+    RenameUser,
+}
+
+impl Action {
+    /// Every action, in the order documented.
+    pub const ALL: [Action; 12] = [
+        Action::Login,
+        Action::CreatePost,
+        Action::EditPost,
+        Action::RekeyPost,
+        Action::AddReaders,
+        Action::DeletePost,
+        Action::ReadFeed,
+        Action::HidePost,
+        Action::DeleteUser,
+        Action::ExportUser,
+        Action::RotateKey,
+        Action::RenameUser,
+    ];
+
+    /// The name of the action, as given in the `action` parameter and stored
+    /// with its tokens.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Login => "login",
+            Action::CreatePost => "create_post",
+            Action::EditPost => "edit_post",
+            Action::RekeyPost => "rekey_post",
+            Action::AddReaders => "add_readers",
+            Action::DeletePost => "delete_post",
+            Action::ReadFeed => "read_feed",
+            Action::HidePost => "hide_post",
+            Action::DeleteUser => "delete_user",
+            Action::ExportUser => "export_user",
+            Action::RotateKey => "rotate_key",
+            Action::RenameUser => "rename_user",
+        }
+    }
+
+    /// Whether a session may authorise the action in place of a proof of
+    /// identity. Only reading the feed and writing posts may be, as sessions
+    /// cannot be revoked. Deleting a post or the account, exporting it,
+    /// rotating its key or renaming it always require a fresh proof.
+    pub fn allows_session(self) -> bool {
+        match self {
+            Action::CreatePost
+            | Action::EditPost
+            | Action::RekeyPost
+            | Action::AddReaders
+            | Action::ReadFeed
+            | Action::HidePost => true,
+            Action::Login
+            | Action::DeletePost
+            | Action::DeleteUser
+            | Action::ExportUser
+            | Action::RotateKey
+            | Action::RenameUser => false,
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for Action {
+    type Error = &'v RawStr;
+
+    fn from_form_value(value: &'v RawStr) -> Result<Action, &'v RawStr> {
+        Action::ALL.iter().cloned().find(|action| action.name() == value.as_str()).ok_or(value)
+    }
+}
+
 /// The `auth` endpoint can be sent a POST request with a body of
 ///
 /// ```json
 /// {
 ///     decryptedToken: "...",
-///     username: "..."
+///     username: "...",
+///     session: Boolean
 /// }
 /// ```
 ///
-/// The response is `200 OK` with a body of either `true` if the validation is
-/// successful, or `false` if the validation is unsuccessful.
+/// The response is `200 OK` if the validation is successful, with a body of
+/// `true`, or if `session` is `true` a body of
+///
+/// ```json
+/// {
+///     session: String,
+///     expires: String
+/// }
+/// ```
+///
+/// where `session` is a session token which may be sent in place of a proof of
+/// identity, as `Authorization: Bearer <session>`, for the actions which allow
+/// it until it expires, or the user's key is rotated. Otherwise
+/// it is `403 Forbidden` with an error of `auth_invalid` if the token is wrong,
+/// or `auth_expired` if the token has timed out, or `404 Not Found` with an
+/// error of `user_not_found`. Only tokens requested for the `login` action are
+/// accepted.
 ///
 /// This will usually be called after a GET request on the same endpoint, which
 /// provides the encrypted data used for this verification as described beneath.
 #[post("/auth", data = "<verify>")]
-pub fn post(conn: CoreDbConn, verify: Json<AuthValidate>) -> Json<bool> {
-    return Json(auth_internal(&conn, &verify.decrypted_token, &verify.username));
+pub fn post(
+    conn: CoreDbConn,
+    challenges: State<Challenges>,
+    verify: Json<AuthValidate>,
+    session_key: State<SessionKey>,
+) -> Result<Json<AuthValidateResponse>, ApiError> {
+    let user_id = auth_internal(
+        &conn,
+        &challenges,
+        &verify.decrypted_token,
+        &verify.username,
+        Action::Login,
+    )?;
+    if !verify.session {
+        return Ok(Json(AuthValidateResponse::Valid(true)));
+    }
+    let public_key = Users.find(user_id).select(UsersPublicKey).first::<String>(&conn.0)?;
+    let (session, expires) = session_key.issue(user_id, &public_key, Utc::now().naive_utc())?;
+    return Ok(Json(AuthValidateResponse::Session(SessionResponse { session, expires })));
 }
 
-pub fn auth_internal(conn: &CoreDbConn, token: &str, username: &str) -> bool {
-    let auth_valid: bool = Users
-        .inner_join(Auth.on(UsersPublicKey.eq(AuthPublicKey)))
-        .select(dsl::count(Username))
-        .filter(Username.eq(username))
-        .filter(ExpectedToken.eq(token))
-        .first::<i64>(&conn.0)
-        != Ok(0);
-
-    if auth_valid {
-        diesel::delete(Auth.filter(ExpectedToken.eq(token))).execute(&conn.0).unwrap();
+/// Validates the identity of `username` for a route which accepts either a
+/// proof of identity or a session, returning their user ID. The proof is used
+/// if one was supplied, and must have been requested for `action`. Otherwise
+/// the action must allow sessions, failing with `ProofRequired` if it does not,
+/// and the session must belong to `username` and the public key they hold.
+pub fn authorize(
+    conn: &CoreDbConn,
+    challenges: &Challenges,
+    session: Result<Session, ApiError>,
+    proof: Option<&str>,
+    username: &str,
+    action: Action,
+) -> Result<i32, ApiError> {
+    if let Some(proof) = proof {
+        return auth_internal(conn, challenges, proof, username, action);
     }
+    if !action.allows_session() {
+        return Err(ApiError::ProofRequired);
+    }
+    let session = session?;
+    let (user_id, public_key) = Users
+        .filter(UsernameKey.eq(username::key(username)))
+        .select((ID, UsersPublicKey))
+        .first::<(i32, String)>(&conn.0)
+        .optional()?
+        .ok_or(ApiError::UserNotFound)?;
+    if !session.belongs_to(user_id, &public_key) {
+        return Err(ApiError::AuthInvalid);
+    }
+    return Ok(user_id);
+}
 
-    return auth_valid;
+/// Validates that `token` is the decrypted form of an outstanding
+/// authentication token of `username` for `action`, consuming that token if it
+/// is, and returns their user ID. This is used by every route which requires
+/// proof of identity.
+///
+/// Stored tokens are looked up by user and compared in constant time, and each
+/// is consumed by exactly one request, even when several race to use it.
+/// Stateless tokens are checked by `Challenges::verify` without writing to the
+/// database, against the in-memory replay cache of this server.
+pub fn auth_internal(
+    conn: &CoreDbConn,
+    challenges: &Challenges,
+    token: &str,
+    username: &str,
+    action: Action,
+) -> Result<i32, ApiError> {
+    let now = Utc::now().naive_utc();
+
+    // ```sql
+    // SELECT ID, PublicKey FROM Users WHERE UsernameKey = {username::key(username)}
+    // LIMIT 1
+    // ```
+    let (user_id, public_key) = Users
+        .filter(UsernameKey.eq(username::key(username)))
+        .select((ID, UsersPublicKey))
+        .first::<(i32, String)>(&conn.0)
+        .optional()?
+        .ok_or(ApiError::UserNotFound)?;
+    if challenges.mode == ChallengeMode::Stateless {
+        challenges.verify(token, user_id, &public_key, action, now)?;
+        return Ok(user_id);
+    }
+
+    // ```sql
+    // SELECT ID, ExpectedToken, Timeout FROM Auth
+    // WHERE PublicKey = {public_key} AND Action = {action}
+    // ```
+    let outstanding = Auth
+        .filter(AuthPublicKey.eq(&public_key))
+        .filter(AuthAction.eq(action.name()))
+        .select((AuthID, ExpectedToken, Timeout))
+        .load::<(i32, String, NaiveDateTime)>(&conn.0)?;
+    let (auth_id, timeout) = outstanding
+        .into_iter()
+        .find(|(_, expected, _)| memcmp(expected.as_bytes(), token.as_bytes()))
+        .map(|(auth_id, _, timeout)| (auth_id, timeout))
+        .ok_or(ApiError::AuthInvalid)?;
+
+    // The token is consumed whether or not it has expired. Only the request
+    // which deletes it may use it.
+    // ```sql
+    // DELETE FROM Auth WHERE ID = {auth_id}
+    // ```
+    if diesel::delete(Auth.find(auth_id)).execute(&conn.0)? != 1 {
+        return Err(ApiError::AuthInvalid);
+    }
+    if timeout.timestamp() < now.timestamp() {
+        return Err(ApiError::AuthExpired);
+    }
+    return Ok(user_id);
 }
 
 /// The `auth` endpoint can be sent a GET request with a query string specifying
-/// it's paramaters of `?username=<USERNAME>`. This will request a new
-/// authentication process be established for the username supplied, and will
-/// send the information necessary for this verification. Because the server
-/// has no knowledge of private keys this is done by sending an encrypted
+/// it's paramaters of `?username=<USERNAME>&action=<ACTION>`. This will request
+/// a new authentication process be established for the username supplied, and
+/// will send the information necessary for this verification. Because the
+/// server has no knowledge of private keys this is done by sending an encrypted
 /// message to the client and expecting them to respond with its decrypted form.
 ///
+/// Each request issues a new token, which is accepted once, only by the route
+/// named by `action` as listed by `Action`, before it times out. A user may
+/// hold a few stored tokens for each action at once, beyond which the oldest is
+/// discarded, while stateless tokens are not limited. The server responds `400
+/// Bad Request` if `action` is missing or unknown.
+///
 /// If the username exists, the server will respond `200 OK` with a body of
 ///
 /// ```json
 /// {
 ///     encryptedToken: String,
 ///     nonce: String,
+///     keyId: Number,
 /// }
 /// ```
 ///
-/// If the username does not exist, the server will respond `404 Not Found`.
-#[get("/auth?<username>")]
+/// where `keyId` identifies the server public key, as listed by the
+/// `server_public_key` endpoint, which must be used to open the token.
+///
+/// If the username does not exist, the server will respond `404 Not Found` with
+/// an error of `user_not_found`, or `307 Temporary Redirect` with an error of
+/// `user_renamed` if it is reserved for a user who has renamed from it.
+///
+/// Requests are rate limited per client, and optionally per existing user, as
+/// configured for `auth` in the `rate_limits` configuration table. Beyond the
+/// limit the server responds `429 Too Many Requests` with an error of
+/// `rate_limited`, and a `Retry-After` header giving the seconds until another
+/// request may be made.
+///
+/// If proof of work is enabled, the request must carry a solved challenge from
+/// the `pow` endpoint in its `X-Proof-Of-Work` header, or the server responds
+/// `428 Precondition Required` with an error of `pow_required` if it is
+/// missing, or `403 Forbidden` with an error of `pow_invalid` if it is not a
+/// valid, unexpired and unspent solution.
+#[get("/auth?<username>&<action>")]
 pub fn get(
     conn: CoreDbConn,
+    throttle: Result<Throttle<AuthRoute>, ApiError>,
+    work: Result<ProofOfWork, ApiError>,
     username: String,
-    server_secret: State<pkc::SecretKey>,
-) -> Result<Json<AuthResponse>, Status> {
+    action: Option<Action>,
+    challenges: State<Challenges>,
+    keyring: State<Keyring>,
+) -> Result<Json<AuthResponse>, ApiError> {
+    let throttle = throttle?;
+    work?;
+    let action = action.ok_or(ApiError::BadRequest)?;
+    return issue_token(&conn, &throttle, &challenges, &username, action, &keyring);
+}
+
+/// Responds to a GET request on the `auth` endpoint once it has been charged to
+/// the rate limit of the client, sealing a new authentication token of
+/// `username` for `action`. Tokens are stored, unless challenges are stateless.
+fn issue_token(
+    conn: &CoreDbConn,
+    throttle: &Throttle<AuthRoute>,
+    challenges: &Challenges,
+    username: &str,
+    action: Action,
+    keyring: &Keyring,
+) -> Result<Json<AuthResponse>, ApiError> {
     let now = Utc::now().naive_utc();
 
-    // Joins Auth with the users. This is done as a left join to enable
-    // detection of any pre-existing authentication keys, and handle them as
-    // appropriate.
     // ```sql
-    // SELECT
-    //  Users.PublicKey
-    //  ExpectedToken
-    //  Timeout
-    // FROM Auth
-    // LEFT JOIN Users ON Users.PublicKey = Auth.PublicKey
-    // WHERE Username = <username>
+    // SELECT ID, PublicKey FROM Users WHERE UsernameKey = {username::key(username)}
     // LIMIT 1
     // ```
-    match Users
-        .left_join(Auth.on(UsersPublicKey.eq(AuthPublicKey)))
-        .select((UsersPublicKey, ExpectedToken.nullable(), Timeout.nullable()))
-        .filter(Username.eq(&username))
-        .first::<(String, Option<String>, Option<NaiveDateTime>)>(&conn.0)
+    let (user_id, public_key) = match Users
+        .filter(UsernameKey.eq(username::key(username)))
+        .select((ID, UsersPublicKey))
+        .first::<(i32, String)>(&conn.0)
+        .optional()?
     {
-        // In the instance where the User already has a token and timeout, and
-        // thus has established a login protocol previously.
-        Ok((public_key, Some(token), Some(timeout))) => {
-            // If the timout has elapsed, and thus the authentication method is
-            // no longer valid: delete the existing authentication method, and
-            // trigger a new internal GET request on this endpoint to process
-            // the users request again with their pre-existing authentication
-            // attempt cleared.
-            if timeout.timestamp() < now.timestamp() {
-                // ```sql
-                // DELETE FROM Auth
-                // WHERE PublicKey = {public_key}
-                // ```
-                diesel::delete(Auth.filter(AuthPublicKey.eq(public_key)))
-                    .execute(&conn.0)
-                    .map_err(|_| Status::InternalServerError)?;
-                return get(conn, username, server_secret);
-            }
-            // Otherwise, the user has a pre-existing authentication token and
-            // it has not timed out, and thus can be re-used. Evaluate the users
-            // public key, and encrypt the message to be used for verification
-            // with their public key and the servers secret key.
-            let public_key =
-                pkc::PublicKey::from_slice(&base64::decode(&public_key).unwrap()).unwrap();
-            let nonce = pkc::gen_nonce();
-            let message =
-                pkc::seal(&base64::decode(&token).unwrap(), &nonce, &public_key, &server_secret);
-            return Ok(Json(AuthResponse {
-                encrypted_token: base64::encode(&message),
-                nonce: base64::encode(&nonce),
-            }));
-        },
+        Some(user) => user,
+        // In the event the user does not exist, respond with a NotFound error,
+        // or redirect to their new username if they have been renamed.
+        None => return Err(user_not_found(conn, username)),
+    };
+    // The username is only charged once the work is done and the user is
+    // known, so that no bucket is kept for usernames which do not exist.
+    throttle.charge_username(username)?;
 
-        // If the user exists, but they do not have an existing authentication
-        // method.
-        Ok((public_key, ..)) => {
-            // Create a new authentication token to be used for validation.
-            let validator: Validator = OsRng::new().expect("Could not Acquire OS Rng").gen();
-            // Store this authentication method, to be accessed by a future GET
-            // request.
-            // ```sql
-            // INSERT INTO Auth
-            // Values({public_key}, {validator}, {now + TIMEOUT_SECONDS})
-            // ```
-            diesel::insert_into(Auth)
-                .values(&AuthInsert {
-                    public_key: &public_key,
-                    expected_token: &base64::encode(&validator),
-                    timeout: now + Duration::seconds(TIMEOUT_SECONDS),
-                })
-                .execute(&conn.0)
-                .unwrap();
-            // Trigger a new internal GET request on this endpoint to process
-            // the users request again with a valid and non timed out method of
-            // authentication having been generated for them.
-            return get(conn, username, server_secret);
-        },
-
-        // In the event the user does not exist, respond with a NotFound error.
-        Err(_) => {
-            return Err(Status::NotFound);
-        },
+    if challenges.mode == ChallengeMode::Stateless {
+        let token = challenges.issue(user_id, &public_key, action, now);
+        return Ok(Json(seal_token(keyring, &public_key, &token)?));
     }
+
+    let token = generate_token();
+    conn.0.transaction::<_, ApiError, _>(|| {
+        // Expired tokens of the user are discarded, as are the oldest for the
+        // action beyond the most which may be held.
+        // ```sql
+        // DELETE FROM Auth WHERE PublicKey = {public_key} AND Timeout < {now};
+        // INSERT INTO Auth (PublicKey, Action, ExpectedToken, Timeout)
+        // VALUES ({public_key}, {action}, {token}, {now + TIMEOUT_SECONDS});
+        // SELECT ID FROM Auth WHERE PublicKey = {public_key} AND Action = {action}
+        // ORDER BY ID DESC;
+        // DELETE FROM Auth WHERE ID IN {all but the first MAX_OUTSTANDING_TOKENS};
+        // ```
+        diesel::delete(Auth.filter(AuthPublicKey.eq(&public_key)).filter(Timeout.lt(now)))
+            .execute(&conn.0)?;
+        diesel::insert_into(Auth)
+            .values(&AuthInsert {
+                public_key: &public_key,
+                action: action.name(),
+                expected_token: &token,
+                timeout: now + Duration::seconds(TIMEOUT_SECONDS),
+            })
+            .execute(&conn.0)?;
+        let mut outstanding = Auth
+            .filter(AuthPublicKey.eq(&public_key))
+            .filter(AuthAction.eq(action.name()))
+            .select(AuthID)
+            .order(AuthID.desc())
+            .load::<i32>(&conn.0)?;
+        if outstanding.len() > MAX_OUTSTANDING_TOKENS {
+            let discarded = outstanding.split_off(MAX_OUTSTANDING_TOKENS);
+            diesel::delete(Auth.filter(AuthID.eq_any(discarded))).execute(&conn.0)?;
+        }
+        Ok(())
+    })?;
+    return Ok(Json(seal_token(keyring, &public_key, &token)?));
+}
+
+/// Generates a new random token, base64 encoded, to be sealed to a user by
+/// `seal_token`.
+pub fn generate_token() -> String {
+    let validator: Validator = OsRng::new().expect("Could not Acquire OS Rng").gen();
+    return base64::encode(&validator);
+}
+
+/// Seals `token` to the holder of `public_key`, both base64 encoded, with the
+/// server's active secret key, so that only they may return it decrypted.
+pub fn seal_token(
+    keyring: &Keyring,
+    public_key: &str,
+    token: &str,
+) -> Result<AuthResponse, ApiError> {
+    let public_key = base64::decode(public_key)
+        .ok()
+        .and_then(|public_key| pkc::PublicKey::from_slice(&public_key))
+        .ok_or(ApiError::Internal)?;
+    let token = base64::decode(token).map_err(|_| ApiError::Internal)?;
+    let nonce = pkc::gen_nonce();
+    let message = pkc::seal(&token, &nonce, &public_key, &keyring.active.secret);
+    return Ok(AuthResponse {
+        encrypted_token: base64::encode(&message),
+        nonce: base64::encode(&nonce),
+        key_id: keyring.active.id,
+    });
 }
@@ -4,5 +4,6 @@
 pub mod auth;
 pub mod noa;
 pub mod post;
+pub mod pow;
 pub mod server_public_key;
 pub mod user;
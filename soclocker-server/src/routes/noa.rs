@@ -1,16 +1,24 @@
+//! Contains the routing control for the `noa` endpoint.
+
 use crate::{
+    challenge::Challenges,
     database::CoreDbConn,
+    error::ApiError,
     models::{NoaOuterResponse, NoaResponse, PostResponse},
+    routes::{
+        auth::{authorize, Action},
+        user::user_not_found,
+    },
     schema::{
         Posts::{
             columns::{
                 Content as PostContent,
                 Nonce as PostNonce,
+                PublicKey as EncryptedPublicKey,
+                PublicKeyNonce as EncryptedPublicKeyNonce,
                 TimePosted,
                 UserID as PostUserID,
                 ID as PostID,
-                PublicKey as EncryptedPublicKey,
-                PublicKeyNonce as EncryptedPublicKeyNonce
             },
             table as Posts,
         },
@@ -28,70 +36,239 @@ use crate::{
             table as NOA,
         },
     },
+    session::Session,
+};
+use chrono::NaiveDateTime;
+use diesel::{BoolExpressionMethods, ExpressionMethods, JoinOnDsl, QueryDsl, RunQueryDsl};
+use rocket::{
+    delete,
+    fairing::{AdHoc, Fairing},
+    get,
+    http::Status,
+    State,
 };
-use diesel::{ExpressionMethods, JoinOnDsl, QueryDsl, RunQueryDsl};
-use rocket::get;
 use rocket_contrib::json::Json;
+use std::{collections::HashMap, convert::TryInto};
+
+/// The Rocket configuration key setting the largest number of posts which may
+/// be requested in a single page of the feed.
+pub const MAX_FEED_LIMIT_CONFIG: &str = "max_feed_limit";
+
+/// The largest page size used when `MAX_FEED_LIMIT_CONFIG` is not set.
+const DEFAULT_MAX_FEED_LIMIT: i64 = 100;
+
+/// The page size used when no `limit` is requested.
+const DEFAULT_FEED_LIMIT: i64 = 25;
+
+/// The length of a decoded cursor: the 8 byte seconds and 4 byte nanoseconds of
+/// the time the post was made, and its 4 byte ID.
+const CURSOR_BYTES: usize = 8 + 4 + 4;
+
+/// The feed limits loaded from the Rocket configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeedConfig {
+    /// The largest number of posts which may be requested in a single page.
+    pub max_limit: i64,
+}
+
+impl FeedConfig {
+    /// Constructs a fairing which loads the feed limits from the Rocket
+    /// configuration on attach, and manages them for use by the `noa` route.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::on_attach("Feed Config", |rocket| {
+            let max_limit =
+                rocket.config().get_int(MAX_FEED_LIMIT_CONFIG).unwrap_or(DEFAULT_MAX_FEED_LIMIT);
+            if max_limit < 1 {
+                rocket::logger::error("The maximum feed limit must be at least 1.");
+                return Err(rocket);
+            }
+            Ok(rocket.manage(FeedConfig { max_limit }))
+        })
+    }
+}
 
-#[get("/noa?<username>&<skip>")]
-pub fn get(conn: CoreDbConn, username: String, skip: Option<i64>) -> Json<NoaOuterResponse> {
-    NOA.inner_join(Users.on(NOAUserID.eq(UserID)))
-        .filter(Username.eq(&username))
-        .select(diesel::dsl::count(NOAPostID))
-        .first(&conn.0)
-        .and_then(|d_count: i64| {
-            Ok(
-                Json(
-                    NoaOuterResponse {
-                        noas:
-                            NOA.inner_join(Users.on(NOAUserID.eq(UserID)))
-                                .inner_join(Posts.on(PostID.eq(NOAPostID)))
-                                .filter(Username.eq(&username))
-                                .limit(25)
-                                .offset(skip.unwrap_or(0) * 25)
-                                .order_by(TimePosted.desc())
-                                .select((NOAPostID, SecretKey, SecretKeyNonce))
-                                .load(&conn.0)
-                                .unwrap_or(vec![])
-                                .into_iter()
-                                .filter_map(
-                                    |(post_id, secret_key, secret_key_nonce): (
-                                        i32,
-                                        String,
-                                        String,
-                                    )|
-                                     -> Option<NoaResponse> {
-                                        Some(NoaResponse {
-                                            post: Posts
-                                                .inner_join(Users.on(UserID.eq(PostUserID)))
-                                                .filter(PostID.eq(post_id))
-                                                .select((
-                                                    PostContent,
-                                                    PostNonce,
-                                                    Username,
-                                                    UserPublicKey,
-                                                    PostID,
-                                                    TimePosted,
-                                                    EncryptedPublicKey,
-                                                    EncryptedPublicKeyNonce,
-                                                ))
-                                                .first::<PostResponse>(&conn.0)
-                                                .ok()?,
-                                            encrypted_secret_key: secret_key,
-                                            nonce: secret_key_nonce,
-                                            all_readers: NOA
-                                                .inner_join(Users.on(UserID.eq(NOAUserID)))
-                                                .filter(NOAPostID.eq(post_id))
-                                                .select(Username)
-                                                .load::<String>(&conn.0).ok()?
-                                        })
-                                    },
-                                )
-                                .collect(),
-                        pages: if d_count % 25 == 0 { d_count / 25 } else { (d_count / 25) + 1 },
-                    },
-                ),
-            )
+/// The `noa` endpoint can be sent a GET request with a query string specifying
+/// its parameters in the format
+/// `?username=<USERNAME>&cursor=<CURSOR>&limit=<LIMIT>&proof=<PROOF>`. This
+/// will return a page of up to `limit` posts the user has been granted access
+/// to, newest first, along with the encrypted secret key needed to read each of
+/// them.
+///
+/// The first page is requested without a `cursor`. Each page includes a
+/// `nextCursor` if there are older posts, which requests the page following it.
+/// Posts made between requests appear only on the first page, so never cause
+/// later pages to skip or repeat posts. The `limit` defaults to 25, and is
+/// capped by the `max_feed_limit` configuration key.
+///
+/// Only the user themselves may read their posts, identified by the optional
+/// `proof`, or if there is none by the session in the `Authorization` header.
+///
+/// It responds `200 OK` with a `NoaOuterResponse`, `400 Bad Request` if the
+/// `cursor` or `limit` is not valid, `401 Unauthorized` with an error of
+/// `auth_required` if neither a proof nor a session is supplied, `403
+/// Forbidden` if the proof of identity or session is not valid for the user,
+/// `307 Temporary Redirect` with an error of `user_renamed` if the username is
+/// reserved for a user who has renamed from it, or `500 Internal Server Error`
+/// with an error of `db_error` if the database fails.
+#[get("/noa?<username>&<cursor>&<limit>&<proof>")]
+pub fn get(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    feed_config: State<FeedConfig>,
+    username: String,
+    cursor: Option<String>,
+    limit: Option<i64>,
+    proof: Option<String>,
+) -> Result<Json<NoaOuterResponse>, ApiError> {
+    let limit = limit.unwrap_or(DEFAULT_FEED_LIMIT).min(feed_config.max_limit);
+    if limit < 1 {
+        return Err(ApiError::BadRequest);
+    }
+    let cursor = cursor.as_ref().map(|cursor| decode_cursor(cursor)).transpose()?;
+
+    let user_id =
+        match authorize(&conn, &challenges, session, proof.as_deref(), &username, Action::ReadFeed)
+        {
+            Err(ApiError::UserNotFound) => return Err(user_not_found(&conn, &username)),
+            user_id => user_id?,
+        };
+
+    // The page is loaded in one query joining each NOA with its post and the
+    // post's author, and the readers of every post on the page in a second.
+    // One more post than the limit is loaded, to find whether there is a next
+    // page.
+    // ```sql
+    // SELECT Posts.*, Users.Username, Users.PublicKey, NOA.SecretKey, NOA.Nonce
+    // FROM NOA
+    // INNER JOIN Posts ON Posts.ID = NOA.PostID
+    // INNER JOIN Users ON Users.ID = Posts.UserID
+    // WHERE NOA.UserID = {user_id}
+    //  AND (TimePosted < {cursor.0} OR (TimePosted = {cursor.0} AND Posts.ID < {cursor.1}))
+    // ORDER BY TimePosted DESC, Posts.ID DESC
+    // LIMIT {limit + 1}
+    // ```
+    let mut query = NOA
+        .inner_join(Posts.on(PostID.eq(NOAPostID)))
+        .inner_join(Users.on(UserID.eq(PostUserID)))
+        .filter(NOAUserID.eq(user_id))
+        .order_by((TimePosted.desc(), PostID.desc()))
+        .limit(limit + 1)
+        .select((
+            (
+                PostContent,
+                PostNonce,
+                Username,
+                UserPublicKey,
+                PostID,
+                TimePosted,
+                EncryptedPublicKey,
+                EncryptedPublicKeyNonce,
+            ),
+            SecretKey,
+            SecretKeyNonce,
+        ))
+        .into_boxed();
+    if let Some((time_posted, post_id)) = cursor {
+        query = query.filter(
+            TimePosted.lt(time_posted).or(TimePosted.eq(time_posted).and(PostID.lt(post_id))),
+        );
+    }
+    let mut noa_rows = query.load::<(PostResponse, String, String)>(&conn.0)?;
+
+    let next_cursor = if noa_rows.len() as i64 > limit {
+        noa_rows.truncate(limit as usize);
+        noa_rows.last().map(|(post, ..)| encode_cursor(post.time_posted, post.post_id))
+    } else {
+        None
+    };
+
+    // ```sql
+    // SELECT NOA.PostID, Users.Username FROM NOA
+    // INNER JOIN Users ON Users.ID = NOA.UserID
+    // WHERE NOA.PostID IN {post_ids}
+    // ```
+    let post_ids = noa_rows.iter().map(|(post, ..)| post.post_id).collect::<Vec<_>>();
+    let mut readers = HashMap::<i32, Vec<String>>::new();
+    for (post_id, reader) in NOA
+        .inner_join(Users.on(UserID.eq(NOAUserID)))
+        .filter(NOAPostID.eq_any(&post_ids))
+        .select((NOAPostID, Username))
+        .load::<(i32, String)>(&conn.0)?
+    {
+        readers.entry(post_id).or_default().push(reader);
+    }
+
+    let noas = noa_rows
+        .into_iter()
+        .map(|(post, secret_key, secret_key_nonce)| {
+            NoaResponse {
+                all_readers: readers.remove(&post.post_id).unwrap_or_default(),
+                post,
+                encrypted_secret_key: secret_key,
+                nonce: secret_key_nonce,
+            }
         })
-        .unwrap()
+        .collect();
+
+    Ok(Json(NoaOuterResponse { noas, next_cursor }))
+}
+
+/// Encodes the position of the post made at `time_posted` with the ID
+/// `post_id` in the feed as an opaque cursor.
+fn encode_cursor(time_posted: NaiveDateTime, post_id: i32) -> String {
+    let mut cursor = Vec::with_capacity(CURSOR_BYTES);
+    cursor.extend_from_slice(&time_posted.timestamp().to_be_bytes());
+    cursor.extend_from_slice(&time_posted.timestamp_subsec_nanos().to_be_bytes());
+    cursor.extend_from_slice(&post_id.to_be_bytes());
+    base64::encode_config(&cursor, base64::URL_SAFE_NO_PAD)
+}
+
+/// Decodes a cursor produced by `encode_cursor`.
+fn decode_cursor(cursor: &str) -> Result<(NaiveDateTime, i32), ApiError> {
+    let cursor =
+        base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).map_err(|_| ApiError::BadRequest)?;
+    if cursor.len() != CURSOR_BYTES {
+        return Err(ApiError::BadRequest);
+    }
+    let seconds = i64::from_be_bytes(cursor[..8].try_into().unwrap());
+    let nanoseconds = u32::from_be_bytes(cursor[8..12].try_into().unwrap());
+    let post_id = i32::from_be_bytes(cursor[12..].try_into().unwrap());
+    let time_posted =
+        NaiveDateTime::from_timestamp_opt(seconds, nanoseconds).ok_or(ApiError::BadRequest)?;
+    Ok((time_posted, post_id))
+}
+
+/// The `noa` endpoint can be sent a DELETE request with a query string
+/// specifying its parameters in the format
+/// `?username=<USERNAME>&post_id=<ID>&proof=<PROOF>`. This removes the post
+/// from the user's feed by deleting their NOA for it, leaving every other
+/// reader's access intact.
+///
+/// The user is identified as for a GET request. It responds `200 OK` on
+/// success, `401 Unauthorized` with an error of `auth_required` if neither a
+/// proof nor a session is supplied, `403 Forbidden` if it is not valid for the
+/// user, and `404 Not Found` with an error of `post_not_found` if the post is
+/// not in their feed.
+#[delete("/noa?<username>&<post_id>&<proof>")]
+pub fn delete(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    username: String,
+    post_id: i32,
+    proof: Option<String>,
+) -> Result<Status, ApiError> {
+    let user_id =
+        authorize(&conn, &challenges, session, proof.as_deref(), &username, Action::HidePost)?;
+
+    // ```sql
+    // DELETE FROM NOA WHERE PostID = {post_id} AND UserID = {user_id}
+    // ```
+    let deleted = diesel::delete(NOA.filter(NOAPostID.eq(post_id)).filter(NOAUserID.eq(user_id)))
+        .execute(&conn.0)?;
+    if deleted == 0 {
+        return Err(ApiError::PostNotFound);
+    }
+    return Ok(Status::Ok);
 }
@@ -1,100 +1,363 @@
 use crate::{
-    routes::auth::auth_internal,
-    database::CoreDbConn,
-    models::{NoaInsert, Post, PostData, PostInsert, PostResponse, User, PostPutData},
+    challenge::Challenges,
+    database::{last_insert_id, CoreDbConn},
+    error::ApiError,
+    models::{
+        NoaInsert,
+        PostCreatedResponse,
+        PostData,
+        PostInsert,
+        PostNOATarget,
+        PostPutData,
+        PostReadersData,
+        PostRekeyData,
+    },
+    routes::auth::{authorize, Action},
     schema::{
         Posts::{
             columns::{
                 Content as PostContent,
                 Nonce as PostNonce,
-                TimePosted,
+                PublicKey as PostPublicKey,
+                PublicKeyNonce as PostPublicKeyNonce,
                 UserID as PostUserID,
                 ID as PostID,
             },
             table as Posts,
         },
         Users::{
-            columns::{PublicKey, Username, ID as UserID},
+            columns::{Username, UsernameKey, ID as UserID},
             table as Users,
         },
-        NOA::table as NOA,
+        NOA::{columns::PostID as NOAPostID, table as NOA},
     },
+    session::Session,
+    username,
+};
+use diesel::{
+    result::{DatabaseErrorKind, Error as DieselError},
+    Connection,
+    ExpressionMethods,
+    JoinOnDsl,
+    OptionalExtension,
+    QueryDsl,
+    RunQueryDsl,
 };
-use chrono::Utc;
-use diesel::{ExpressionMethods, JoinOnDsl, QueryDsl, RunQueryDsl};
-use rocket::{get, http::Status, post, put};
+use rocket::{delete, http::Status, post, put, State};
 use rocket_contrib::json::Json;
 
+/// The `post` endpoint can be sent a POST request with a body of `PostData`,
+/// creating a new post and granting each of its NOA targets access to it.
+///
+/// It responds `200 OK` with a body of
+///
+/// ```json
+/// {
+///     postId: Number,
+///     failedReaders: [String]
+/// }
+/// ```
+///
+/// where `failedReaders` lists each NOA target which could not be granted
+/// access. The author is identified by the `proof` in the body, or if there is
+/// none by the session in the `Authorization` header. It responds `401
+/// Unauthorized` if neither is supplied, `403 Forbidden` if the proof of
+/// identity or session is not valid, and `404 Not Found` with an error of
+/// `user_not_found` if the author does not exist.
+///
+/// The post and its NOAs are created in a single transaction, so a failure
+/// part way through never leaves a partially shared post behind. The proof of
+/// identity is consumed within the same transaction, so remains valid if the
+/// post could not be created.
 #[post("/post", data = "<post_data>")]
-pub fn post(conn: CoreDbConn, post_data: Json<PostData>) -> Result<Json<bool>, Status> {
+pub fn post(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    post_data: Json<PostData>,
+) -> Result<Json<PostCreatedResponse>, ApiError> {
     let post_data = post_data.into_inner();
 
-    let post_creator_id = Users
-        .filter(Username.eq(&post_data.username))
-        .select(UserID)
-        .first::<i32>(&conn.0)
-        .map_err(|_| Status::InternalServerError)?;
+    let response = conn.0.transaction::<_, ApiError, _>(|| {
+        let post_creator_id = authorize(
+            &conn,
+            &challenges,
+            session,
+            post_data.proof.as_deref(),
+            &post_data.username,
+            Action::CreatePost,
+        )?;
 
-    let now = diesel::dsl::now;
+        diesel::insert_into(Posts)
+            .values(&PostInsert {
+                content: &post_data.content,
+                nonce: &post_data.nonce,
+                user_id: post_creator_id,
+                time_posted: diesel::dsl::now,
+                public_key: &post_data.public_key,
+                public_key_nonce: &post_data.public_key_nonce,
+            })
+            .execute(&conn.0)?;
+        let post_id = last_insert_id(&conn.0)?;
 
-    if !auth_internal(&conn, &post_data.proof, &post_data.username) {
-        return Err(Status::Forbidden);
-    }
+        let failed_readers = grant_readers(&conn, post_id, &post_data.noa_encrypted_keys)?;
+        Ok(PostCreatedResponse { post_id, failed_readers })
+    })?;
 
-    let inserted_post_id = diesel::insert_into(Posts)
-        .values(&PostInsert {
-            content: &post_data.content,
-            nonce: &post_data.nonce,
-            user_id: post_creator_id,
-            time_posted: now,
-            public_key: &post_data.public_key,
-            public_key_nonce: &post_data.public_key_nonce
-        })
-        .execute(&conn.0)
-        .and_then(|_| {
-            Posts
-                .filter(PostNonce.eq(&post_data.nonce))
-                .filter(PostUserID.eq(post_creator_id))
-                .select(PostID)
-                .first::<i32>(&conn.0)
-        })
-        .map_err(|_| Status::InternalServerError)?;
-
-    Ok(Json(post_data.noa_encrypted_keys.into_iter().all(|noa| {
-        Users
-            .filter(Username.eq(&noa.username))
-            .first::<User>(&conn.0)
-            .and_then(|noa_user| {
-                diesel::insert_into(NOA)
-                    .values(&NoaInsert {
-                        user_id: noa_user.id,
-                        post_id: inserted_post_id,
-                        secret_key: &noa.encrypted_secret_key,
-                        nonce: &noa.nonce,
-                    })
-                    .execute(&conn.0)
-            })
-            .is_ok()
-    })))
+    return Ok(Json(response));
 }
 
+/// The `post` endpoint can be sent a PUT request with a body of `PostPutData`,
+/// replacing the content of a post with a new version encrypted under the same
+/// key.
+///
+/// The author is identified as for a POST request. It responds `200 OK` on
+/// success, `401 Unauthorized` if no proof of identity or session is supplied,
+/// `403 Forbidden` if it is not valid for the author of the post, and `404 Not
+/// Found` with an error of `post_not_found` if the post does not exist. The
+/// proof of identity is consumed in the same transaction as the edit, so
+/// remains valid if the post could not be edited.
 #[put("/post", data = "<put_data>")]
-pub fn put(conn: CoreDbConn, put_data: Json<PostPutData>) -> Result<Status, Status> {
+pub fn put(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    put_data: Json<PostPutData>,
+) -> Result<Status, ApiError> {
     let put_data = put_data.into_inner();
-    let (username, post_id) = Posts
+
+    conn.0.transaction::<_, ApiError, _>(|| {
+        let username = post_author(&conn, put_data.post_id)?;
+        authorize(
+            &conn,
+            &challenges,
+            session,
+            put_data.proof.as_deref(),
+            &username,
+            Action::EditPost,
+        )?;
+
+        diesel::update(Posts.filter(PostID.eq(put_data.post_id)))
+            .set((PostContent.eq(&put_data.new_content), PostNonce.eq(&put_data.new_nonce)))
+            .execute(&conn.0)?;
+        Ok(())
+    })?;
+    return Ok(Status::Ok);
+}
+
+/// The `post/rekey` endpoint can be sent a PUT request with a body of
+/// `PostRekeyData`, replacing a post with a new version encrypted under a new
+/// key, and replacing its NOAs with a fresh set granting access to that key.
+/// This is how access is revoked: every reader not given a new NOA loses
+/// access to the new version.
+///
+/// The author is identified as for a POST request to the `post` endpoint. The
+/// post is replaced in a single transaction, so its previous readers never
+/// keep access to the new version, and the proof of identity is consumed
+/// within it, so remains valid if the post could not be replaced. It responds
+/// `200 OK` with a body of
+///
+/// ```json
+/// {
+///     postId: Number,
+///     failedReaders: [String]
+/// }
+/// ```
+///
+/// where `failedReaders` lists each NOA target which could not be granted
+/// access. It responds `401 Unauthorized` if no proof of identity or session is
+/// supplied, `403 Forbidden` if it is not valid for the author of the post, and
+/// `404 Not Found` with an error of `post_not_found` if the post does not
+/// exist.
+#[put("/post/rekey", data = "<rekey_data>")]
+pub fn rekey(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    rekey_data: Json<PostRekeyData>,
+) -> Result<Json<PostCreatedResponse>, ApiError> {
+    let rekey_data = rekey_data.into_inner();
+    let post_id = rekey_data.post_id;
+
+    let response = conn.0.transaction::<_, ApiError, _>(|| {
+        let username = post_author(&conn, post_id)?;
+        authorize(
+            &conn,
+            &challenges,
+            session,
+            rekey_data.proof.as_deref(),
+            &username,
+            Action::RekeyPost,
+        )?;
+
+        // ```sql
+        // UPDATE Posts
+        // SET Content = {content}, Nonce = {nonce}, PublicKey = {public_key},
+        //  PublicKeyNonce = {public_key_nonce}
+        // WHERE ID = {post_id}
+        // ```
+        diesel::update(Posts.filter(PostID.eq(post_id)))
+            .set((
+                PostContent.eq(&rekey_data.content),
+                PostNonce.eq(&rekey_data.nonce),
+                PostPublicKey.eq(&rekey_data.public_key),
+                PostPublicKeyNonce.eq(&rekey_data.public_key_nonce),
+            ))
+            .execute(&conn.0)?;
+
+        // ```sql
+        // DELETE FROM NOA WHERE PostID = {post_id}
+        // ```
+        diesel::delete(NOA.filter(NOAPostID.eq(post_id))).execute(&conn.0)?;
+
+        let failed_readers = grant_readers(&conn, post_id, &rekey_data.noa_encrypted_keys)?;
+        Ok(PostCreatedResponse { post_id, failed_readers })
+    })?;
+
+    return Ok(Json(response));
+}
+
+/// The `post/readers` endpoint can be sent a POST request with a body of
+/// `PostReadersData`, granting each of its NOA targets access to an existing
+/// post, under the key it is already encrypted with.
+///
+/// The author is identified as for a POST request to the `post` endpoint. It
+/// responds `200 OK` with a body of
+///
+/// ```json
+/// {
+///     postId: Number,
+///     failedReaders: [String]
+/// }
+/// ```
+///
+/// where `failedReaders` lists each NOA target which could not be granted
+/// access, because no such user exists or they can already read the post. It
+/// responds `401 Unauthorized` if no proof of identity or session is supplied,
+/// `403 Forbidden` if it is not valid for the author of the post, and `404 Not
+/// Found` with an error of `post_not_found` if the post does not exist. The
+/// proof of identity is consumed in the same transaction as the readers are
+/// granted access, so remains valid if they could not be.
+#[post("/post/readers", data = "<readers_data>")]
+pub fn add_readers(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    readers_data: Json<PostReadersData>,
+) -> Result<Json<PostCreatedResponse>, ApiError> {
+    let readers_data = readers_data.into_inner();
+    let post_id = readers_data.post_id;
+
+    let failed_readers = conn.0.transaction::<_, ApiError, _>(|| {
+        let username = post_author(&conn, post_id)?;
+        authorize(
+            &conn,
+            &challenges,
+            session,
+            readers_data.proof.as_deref(),
+            &username,
+            Action::AddReaders,
+        )?;
+
+        grant_readers(&conn, post_id, &readers_data.noa_encrypted_keys)
+    })?;
+
+    return Ok(Json(PostCreatedResponse { post_id, failed_readers }));
+}
+
+/// The `post` endpoint can be sent a DELETE request with a query string
+/// specifying its parameters in the format `?post_id=<ID>&proof=<PROOF>`,
+/// deleting the post along with every NOA granting access to it.
+///
+/// The author is identified by `proof`, as a session is not accepted for
+/// deleting a post. It responds `200 OK` on success, `401 Unauthorized` with an
+/// error of `proof_required` if no proof is supplied, `403 Forbidden` if it is
+/// not valid for the author of the post, and `404 Not Found` with an error of
+/// `post_not_found` if the post does not exist. The proof of identity is
+/// consumed in the same transaction as the deletion, so remains valid if the
+/// post could not be deleted.
+#[delete("/post?<post_id>&<proof>")]
+pub fn delete(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    post_id: i32,
+    proof: Option<String>,
+) -> Result<Status, ApiError> {
+    conn.0.transaction::<_, ApiError, _>(|| {
+        let username = post_author(&conn, post_id)?;
+        authorize(&conn, &challenges, session, proof.as_deref(), &username, Action::DeletePost)?;
+
+        // ```sql
+        // DELETE FROM NOA WHERE PostID = {post_id};
+        // DELETE FROM Posts WHERE ID = {post_id};
+        // ```
+        diesel::delete(NOA.filter(NOAPostID.eq(post_id))).execute(&conn.0)?;
+        diesel::delete(Posts.filter(PostID.eq(post_id))).execute(&conn.0)?;
+        Ok(())
+    })?;
+    return Ok(Status::Ok);
+}
+
+/// Returns the username of the author of the post with the ID `post_id`.
+fn post_author(conn: &CoreDbConn, post_id: i32) -> Result<String, ApiError> {
+    // ```sql
+    // SELECT Username FROM Posts
+    // INNER JOIN Users ON Users.ID = Posts.UserID
+    // WHERE Posts.ID = {post_id}
+    // ```
+    Posts
         .inner_join(Users.on(UserID.eq(PostUserID)))
-        .filter(PostID.eq(&put_data.post_id))
-        .select((Username, PostID))
-        .first::<(String, i32)>(&conn.0)
-        .map_err(|_| Status::NotFound)?;
+        .filter(PostID.eq(post_id))
+        .select(Username)
+        .first::<String>(&conn.0)
+        .optional()?
+        .ok_or(ApiError::PostNotFound)
+}
 
-    if !auth_internal(&conn, &put_data.proof, &username) {
-        return Err(Status::Forbidden);
-    }
+/// Grants each of `targets` access to the post with the ID `post_id`, returning
+/// the username of each target which could not be granted access, because no
+/// such user exists or they already have access. This must be called
+/// within a transaction.
+fn grant_readers(
+    conn: &CoreDbConn,
+    post_id: i32,
+    targets: &[PostNOATarget],
+) -> Result<Vec<String>, ApiError> {
+    let mut failed_readers = vec![];
+    for noa in targets {
+        let noa_user_id = Users
+            .filter(UsernameKey.eq(username::key(&noa.username)))
+            .select(UserID)
+            .first::<i32>(&conn.0)
+            .optional()?;
+        let noa_user_id = match noa_user_id {
+            Some(noa_user_id) => noa_user_id,
+            None => {
+                failed_readers.push(noa.username.clone());
+                continue;
+            },
+        };
 
-    return Ok(diesel::update(Posts.filter(PostID.eq(post_id)))
-        .set((PostContent.eq(&put_data.new_content), PostNonce.eq(&put_data.new_nonce)))
-        .execute(&conn.0)
-        .map(|_| Status::Ok)
-        .map_err(|_| Status::InternalServerError)?);
-}
\ No newline at end of file
+        // Each NOA is inserted within a savepoint, so that a reader listed
+        // twice is reported rather than aborting the whole transaction.
+        let inserted = conn.0.transaction(|| {
+            diesel::insert_into(NOA)
+                .values(&NoaInsert {
+                    user_id: noa_user_id,
+                    post_id,
+                    secret_key: &noa.encrypted_secret_key,
+                    nonce: &noa.nonce,
+                })
+                .execute(&conn.0)
+        });
+        match inserted {
+            Ok(_) => {},
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => {
+                failed_readers.push(noa.username.clone());
+            },
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(failed_readers)
+}
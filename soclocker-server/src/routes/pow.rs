@@ -0,0 +1,26 @@
+//! Contains the routing control for the `pow` endpoint.
+
+use crate::{models::PowChallenge, pow::Challenger};
+use chrono::Utc;
+use rocket::{get, State};
+use rocket_contrib::json::Json;
+
+/// The `pow` endpoint can be sent a GET request which should always respond
+/// `200 OK` with the body
+///
+/// ```json
+/// {
+///     salt: "...",
+///     difficulty: 16,
+///     expires: "2019-07-01T12:05:00"
+/// }
+/// ```
+///
+/// containing a new proof of work challenge. A solution must be sent in the
+/// `X-Proof-Of-Work` header of requests to the `auth` endpoint and
+/// registrations, as described in the `pow` module. When proof of work is
+/// disabled the difficulty is `0`, and the header may be omitted.
+#[get("/pow")]
+pub fn get(challenger: State<Challenger>) -> Json<PowChallenge> {
+    Json(challenger.challenge(Utc::now().naive_utc()))
+}
@@ -1,15 +1,43 @@
 //! Contains the routing control for the `server_public_key` endpoint.
 
+use crate::{
+    keys::Keyring,
+    models::{ServerPublicKey, ServerPublicKeysResponse},
+};
+use chrono::Utc;
 use rocket::{get, State};
 use rocket_contrib::json::Json;
-use sodiumoxide::crypto::box_ as pkc;
 
 /// The `server_public_key` endpoint can be sent a GET request which should
-/// always respond `200 OK` with the body: `"..."` containing the servers public
-/// key. This should never change once the server is deployed, as changes to it
-/// will break all existing publically visible content and authentication
-/// records.
+/// always respond `200 OK` with the body
+///
+/// ```json
+/// {
+///     activeKeyId: 2,
+///     keys: [
+///         { id: 2, publicKey: "...", expires: null },
+///         { id: 1, publicKey: "...", expires: "2019-07-01T12:00:00" }
+///     ]
+/// }
+/// ```
+///
+/// containing the servers active public key, followed by any retired public
+/// keys which are still valid. Authentication responses state the `keyId` of
+/// the key their token was sealed with, so that the server keys can be rotated
+/// without breaking clients which fetched a token before the rotation.
 #[get("/server_public_key")]
-pub fn get(server_public: State<pkc::PublicKey>) -> Json<String> {
-    Json(base64::encode(&server_public.0))
+pub fn get(keyring: State<Keyring>) -> Json<ServerPublicKeysResponse> {
+    Json(ServerPublicKeysResponse {
+        active_key_id: keyring.active.id,
+        keys: keyring
+            .valid_at(Utc::now().naive_utc())
+            .map(|keypair| {
+                ServerPublicKey {
+                    id: keypair.id,
+                    public_key: base64::encode(&keypair.public.0),
+                    expires: keypair.expires,
+                }
+            })
+            .collect(),
+    })
 }
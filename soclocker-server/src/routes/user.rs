@@ -1,13 +1,153 @@
 //! Contains the routing control for the `user` endpoint.
 
 use crate::{
+    challenge::Challenges,
     database::CoreDbConn,
-    models::{User, UserInsert},
-    schema::Users::{columns::Username, table as Users},
+    error::ApiError,
+    keys::Keyring,
+    models::{
+        AuthResponse,
+        NoaExport,
+        PendingRegistrationInsert,
+        PostResponse,
+        User,
+        UserConfirm,
+        UserData,
+        UserExport,
+        UserInsert,
+        UserKeyData,
+        UsernameData,
+        UsernameReservationInsert,
+    },
+    pow::ProofOfWork,
+    rate_limit::{RegisterRoute, Throttle},
+    routes::auth::{authorize, generate_token, seal_token, Action},
+    schema::{
+        Auth::{columns::PublicKey as AuthPublicKey, table as Auth},
+        PendingRegistrations::{
+            columns::{
+                ExpectedToken as PendingToken,
+                PublicKey as PendingPublicKey,
+                Timeout as PendingTimeout,
+                Username as PendingUsername,
+                UsernameKey as PendingUsernameKey,
+            },
+            table as PendingRegistrations,
+        },
+        Posts::{
+            columns::{
+                Content as PostContent,
+                Nonce as PostNonce,
+                PublicKey as EncryptedPublicKey,
+                PublicKeyNonce as EncryptedPublicKeyNonce,
+                TimePosted,
+                UserID as PostUserID,
+                ID as PostID,
+            },
+            table as Posts,
+        },
+        UsernameReservations::{
+            columns::{Expires, UserID as ReservationUserID, Username as ReservedUsername},
+            table as UsernameReservations,
+        },
+        Users::{
+            columns::{PublicKey as UserPublicKey, Username, UsernameKey, ID as UserID},
+            table as Users,
+        },
+        NOA::{
+            columns::{
+                Nonce as SecretKeyNonce,
+                PostID as NOAPostID,
+                SecretKey,
+                UserID as NOAUserID,
+            },
+            table as NOA,
+        },
+    },
+    session::Session,
+    username,
+    TIMEOUT_SECONDS,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{
+    result::{DatabaseErrorKind, Error as DieselError},
+    BoolExpressionMethods,
+    Connection,
+    ExpressionMethods,
+    JoinOnDsl,
+    OptionalExtension,
+    QueryDsl,
+    RunQueryDsl,
+};
+use rocket::{
+    delete,
+    fairing::{AdHoc, Fairing},
+    get,
+    http::Status,
+    post,
+    put,
+    State,
 };
-use diesel::{ExpressionMethods, QueryDsl, RunQueryDsl};
-use rocket::{get, http::Status, post};
 use rocket_contrib::json::Json;
+use sodiumoxide::{crypto::box_ as pkc, utils::memcmp};
+
+/// The Rocket configuration key setting the number of days a username is
+/// reserved for a user after they rename from it.
+pub const USERNAME_RESERVATION_CONFIG: &str = "username_reservation_days";
+
+/// The reservation period used when `USERNAME_RESERVATION_CONFIG` is not set.
+const DEFAULT_USERNAME_RESERVATION_DAYS: i64 = 30;
+
+/// The username settings loaded from the Rocket configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsernameConfig {
+    /// How long a username is reserved for a user after they rename from it.
+    pub reservation: Duration,
+}
+
+impl UsernameConfig {
+    /// Constructs a fairing which loads the username settings from the Rocket
+    /// configuration on attach, and manages them for use by the `user` routes.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::on_attach("Username Config", |rocket| {
+            let days = rocket
+                .config()
+                .get_int(USERNAME_RESERVATION_CONFIG)
+                .unwrap_or(DEFAULT_USERNAME_RESERVATION_DAYS);
+            if days < 0 {
+                rocket::logger::error("The username reservation period must not be negative.");
+                return Err(rocket);
+            }
+            Ok(rocket.manage(UsernameConfig { reservation: Duration::days(days) }))
+        })
+    }
+}
+
+/// Returns the error for a lookup of `username` which matched no user. This is
+/// `UserRenamed` if the username is reserved for a user who has renamed from
+/// it, so that the lookup is redirected to their new username, and otherwise
+/// `UserNotFound`.
+pub fn user_not_found(conn: &CoreDbConn, username: &str) -> ApiError {
+    // ```sql
+    // SELECT Users.Username FROM UsernameReservations
+    // INNER JOIN Users ON Users.ID = UsernameReservations.UserID
+    // WHERE UsernameReservations.Username = {username::key(username)}
+    //  AND Expires > {now}
+    // LIMIT 1
+    // ```
+    let renamed = UsernameReservations
+        .inner_join(Users.on(UserID.eq(ReservationUserID)))
+        .filter(ReservedUsername.eq(username::key(username)))
+        .filter(Expires.gt(Utc::now().naive_utc()))
+        .select(Username)
+        .first::<String>(&conn.0)
+        .optional();
+    match renamed {
+        Ok(Some(username)) => ApiError::UserRenamed(username),
+        Ok(None) => ApiError::UserNotFound,
+        Err(e) => e.into(),
+    }
+}
 
 /// The `user` endpoint can be sent a GET request with a query string specifying
 /// it's parameters in the format `?username=<USERNAME>`. This will return the
@@ -23,16 +163,26 @@ use rocket_contrib::json::Json;
 /// }
 /// ```
 ///
-/// or `404 Not Found` if the user does not exist.
+/// `307 Temporary Redirect` with an error of `user_renamed` if the username is
+/// reserved for a user who has renamed from it, or `404 Not Found` with an
+/// error of `user_not_found` if the user does not exist.
 #[get("/user?<username>")]
-pub fn get<'a>(conn: CoreDbConn, username: String) -> Option<Json<User>> {
+pub fn get<'a>(conn: CoreDbConn, username: String) -> Result<Json<User>, ApiError> {
     // ```json
     // Select ID, PublicKey, Username
     // FROM Users
-    // WHERE Username = {username}
+    // WHERE UsernameKey = {username::key(username)}
     // LIMIT 1
     // ```
-    Some(Json(Users.filter(Username.eq(&username)).first::<User>(&conn.0).ok()?))
+    let user = Users
+        .filter(UsernameKey.eq(username::key(&username)))
+        .select((UserID, UserPublicKey, Username))
+        .first::<User>(&conn.0)
+        .optional()?;
+    match user {
+        Some(user) => Ok(Json(user)),
+        None => Err(user_not_found(&conn, &username)),
+    }
 }
 
 /// The `user` endpoint can be sent a POST request with a body of
@@ -44,18 +194,587 @@ pub fn get<'a>(conn: CoreDbConn, username: String) -> Option<Json<User>> {
 /// }
 /// ```
 ///
-/// and it will attempt to insert them into the database. Responding
-/// `201 Created` on success, `409 Conflict` if a user of that name already
-/// exists, and `500 Internal Server Error` if there is a database error.
+/// to begin registering the user. The username must be between 1 and 100
+/// letters, digits, `.`, `-` or `_`, and is stored in Unicode Normalisation
+/// Form C. The public key must be the base64 encoding of a `box_` public key.
+///
+/// As only the holder of the secret key may register its public key, the
+/// server responds `200 OK` with a body of
+///
+/// ```json
+/// {
+///     encryptedToken: String,
+///     nonce: String,
+///     keyId: Number,
+/// }
+/// ```
+///
+/// sealing a registration token to the public key, as the `auth` endpoint
+/// does. The user is created once the token is returned decrypted to the
+/// `user/confirm` endpoint, before it times out. Registrations of a public key
+/// under different usernames await confirmation independently, and repeating a
+/// registration which has not timed out seals the same token again, so that no
+/// one else, as public keys are public, may cancel it. Registrations which are
+/// never confirmed time out.
+///
+/// Otherwise it responds `400 Bad Request` with an error of `invalid_username`
+/// or `invalid_public_key` if either is not valid, `409 Conflict` with an error
+/// of `username_taken` if a user of the same name, ignoring case, already
+/// exists or the name is reserved for a user who renamed from it, or of
+/// `conflict` if the public key is already registered, `429 Too Many Requests`
+/// with an error of `rate_limited` and a `Retry-After` header if the client or
+/// username has exceeded the `register` rate limits, `428 Precondition
+/// Required` with an error of `pow_required` or `403 Forbidden` with an error
+/// of `pow_invalid` if proof of work is enabled and no valid solution is given,
+/// as for the `auth` endpoint, and `500 Internal Server Error` if there is a
+/// database error.
 #[post("/user", data = "<user_data>")]
-pub fn post(conn: CoreDbConn, user_data: Json<UserInsert>) -> Result<Status, Status> {
-    // SELECT Username FROM User WHERE Username = {user_data.username}
-    if Users.filter(Username.eq(&user_data.username)).first::<User>(&conn.0).is_ok() {
-        return Err(Status::Conflict);
-    }
-    if let Ok(_) = diesel::insert_into(Users).values(&user_data.into_inner()).execute(&conn.0) {
-        return Ok(Status::Created);
-    } else {
-        return Err(Status::InternalServerError);
+pub fn post(
+    conn: CoreDbConn,
+    throttle: Result<Throttle<RegisterRoute>, ApiError>,
+    work: Result<ProofOfWork, ApiError>,
+    keyring: State<Keyring>,
+    user_data: Json<UserData>,
+) -> Result<Json<AuthResponse>, ApiError> {
+    let throttle = throttle?;
+    work?;
+    let (username, username_key) = username::validate(&user_data.username)?;
+    throttle.charge_username(&username)?;
+    validate_public_key(&user_data.public_key)?;
+
+    // ```sql
+    // SELECT ID FROM Users WHERE UsernameKey = {username_key} LIMIT 1;
+    // SELECT ID FROM Users WHERE PublicKey = {user_data.public_key} LIMIT 1;
+    // ```
+    let holder = Users
+        .filter(UsernameKey.eq(&username_key))
+        .select(UserID)
+        .first::<i32>(&conn.0)
+        .optional()?;
+    if holder.is_some() || reserved_for(&conn, &username_key)?.is_some() {
+        return Err(ApiError::UsernameTaken);
+    }
+    let registered = Users
+        .filter(UserPublicKey.eq(&user_data.public_key))
+        .select(UserID)
+        .first::<i32>(&conn.0)
+        .optional()?;
+    if registered.is_some() {
+        return Err(ApiError::Conflict);
+    }
+
+    let now = Utc::now().naive_utc();
+    let token = conn.0.transaction::<_, ApiError, _>(|| {
+        // ```sql
+        // SELECT ExpectedToken FROM PendingRegistrations
+        // WHERE UsernameKey = {username_key} AND PublicKey = {user_data.public_key}
+        //  AND Timeout >= {now}
+        // ```
+        let pending = PendingRegistrations
+            .find((&username_key, &user_data.public_key))
+            .filter(PendingTimeout.ge(now))
+            .select(PendingToken)
+            .first::<String>(&conn.0)
+            .optional()?;
+        if let Some(token) = pending {
+            return Ok(token);
+        }
+
+        // ```sql
+        // DELETE FROM PendingRegistrations
+        // WHERE UsernameKey = {username_key} AND PublicKey = {user_data.public_key};
+        // INSERT INTO PendingRegistrations
+        // VALUES ({user_data.public_key}, {username}, {username_key}, {token},
+        //  {now + TIMEOUT_SECONDS});
+        // ```
+        let token = generate_token();
+        diesel::delete(PendingRegistrations.find((&username_key, &user_data.public_key)))
+            .execute(&conn.0)?;
+        diesel::insert_into(PendingRegistrations)
+            .values(&PendingRegistrationInsert {
+                public_key: &user_data.public_key,
+                username: &username,
+                username_key: &username_key,
+                expected_token: &token,
+                timeout: now + Duration::seconds(TIMEOUT_SECONDS),
+            })
+            .execute(&conn.0)?;
+        Ok(token)
+    })?;
+    return Ok(Json(seal_token(&keyring, &user_data.public_key, &token)?));
+}
+
+/// The `user/confirm` endpoint can be sent a POST request with a body of
+///
+/// ```json
+/// {
+///     username: "...",
+///     decryptedToken: "..."
+/// }
+/// ```
+///
+/// where `decryptedToken` is the registration token returned by the `user`
+/// endpoint, decrypted and base64 encoded. This creates the user, consuming the
+/// token whether or not it has timed out.
+///
+/// It responds `201 Created` on success, `403 Forbidden` with an error of
+/// `auth_invalid` if the token does not match a registration of the username
+/// awaiting confirmation, or `auth_expired` if it has timed out, and `409
+/// Conflict` with an error of `username_taken` if the username, ignoring case,
+/// has been registered or reserved since, or of `conflict` if the public key
+/// has been registered since.
+#[post("/user/confirm", data = "<confirm>")]
+pub fn confirm(conn: CoreDbConn, confirm: Json<UserConfirm>) -> Result<Status, ApiError> {
+    let username_key = username::key(&confirm.username);
+
+    // Tokens are compared in constant time rather than by the query.
+    // ```sql
+    // SELECT PublicKey, Username, ExpectedToken, Timeout FROM PendingRegistrations
+    // WHERE UsernameKey = {username_key}
+    // ```
+    let (public_key, username, _, timeout) = PendingRegistrations
+        .filter(PendingUsernameKey.eq(&username_key))
+        .select((PendingPublicKey, PendingUsername, PendingToken, PendingTimeout))
+        .load::<(String, String, String, NaiveDateTime)>(&conn.0)?
+        .into_iter()
+        .find(|(_, _, expected, _)| memcmp(expected.as_bytes(), confirm.decrypted_token.as_bytes()))
+        .ok_or(ApiError::AuthInvalid)?;
+    // Only the request which deletes the registration may confirm it.
+    let registration = PendingRegistrations.find((&username_key, &public_key));
+    if diesel::delete(registration).execute(&conn.0)? != 1 {
+        return Err(ApiError::AuthInvalid);
+    }
+    if timeout.timestamp() < Utc::now().naive_utc().timestamp() {
+        return Err(ApiError::AuthExpired);
+    }
+    if reserved_for(&conn, &username_key)?.is_some() {
+        return Err(ApiError::UsernameTaken);
+    }
+
+    // Names in use are refused by the uniqueness of their key, which unlike a
+    // query beforehand cannot race with concurrent registrations.
+    diesel::insert_into(Users)
+        .values(&UserInsert {
+            public_key: &public_key,
+            username: &username,
+            username_key: &username_key,
+        })
+        .execute(&conn.0)
+        .map_err(|e| username_conflict(&conn, &username_key, e))?;
+    return Ok(Status::Created);
+}
+
+/// The `user` endpoint can be sent a DELETE request with a query string
+/// specifying its parameters in the format
+/// `?username=<USERNAME>&proof=<PROOF>`. This deletes the user's account, along
+/// with every post they authored, every NOA granting access to those posts or
+/// granting them access to any post, any outstanding authentication token, and
+/// the reservations of any username they renamed from.
+///
+/// The user is identified by `proof`, as a session is not accepted for deleting
+/// the account. It responds `200 OK` on success, `401 Unauthorized` with an
+/// error of `proof_required` if no proof is supplied, `403 Forbidden` if it is
+/// not valid for the user, and `404 Not Found` with an error of
+/// `user_not_found` if the user does not exist.
+#[delete("/user?<username>&<proof>")]
+pub fn delete(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    username: String,
+    proof: Option<String>,
+) -> Result<Status, ApiError> {
+    let user_id =
+        authorize(&conn, &challenges, session, proof.as_deref(), &username, Action::DeleteUser)?;
+
+    conn.0.transaction::<_, ApiError, _>(|| {
+        let public_key = Users.find(user_id).select(UserPublicKey).first::<String>(&conn.0)?;
+
+        // ```sql
+        // DELETE FROM NOA
+        // WHERE UserID = {user_id}
+        //  OR PostID IN (SELECT ID FROM Posts WHERE UserID = {user_id});
+        // DELETE FROM Posts WHERE UserID = {user_id};
+        // DELETE FROM Auth WHERE PublicKey = {public_key};
+        // DELETE FROM UsernameReservations WHERE UserID = {user_id};
+        // DELETE FROM Users WHERE ID = {user_id};
+        // ```
+        let authored = Posts.filter(PostUserID.eq(user_id)).select(PostID);
+        diesel::delete(NOA.filter(NOAUserID.eq(user_id).or(NOAPostID.eq_any(authored))))
+            .execute(&conn.0)?;
+        diesel::delete(Posts.filter(PostUserID.eq(user_id))).execute(&conn.0)?;
+        diesel::delete(Auth.filter(AuthPublicKey.eq(&public_key))).execute(&conn.0)?;
+        diesel::delete(UsernameReservations.filter(ReservationUserID.eq(user_id)))
+            .execute(&conn.0)?;
+        diesel::delete(Users.find(user_id)).execute(&conn.0)?;
+        Ok(())
+    })?;
+    return Ok(Status::Ok);
+}
+
+/// The `user/export` endpoint can be sent a GET request with a query string
+/// specifying its parameters in the format
+/// `?username=<USERNAME>&proof=<PROOF>`. This returns everything the server
+/// holds about the user as a `UserExport`: their user record, every post they
+/// authored or were granted access to, every NOA on a post they authored, every
+/// NOA granting them access, and the usernames reserved for them. As each post
+/// is included with the NOA granting the user access to it, the archive can be
+/// decrypted offline with their secret key, as the client decrypts the feed.
+///
+/// The user is identified as for a DELETE request, by `proof` alone. It
+/// responds `200 OK` on success, `401 Unauthorized` with an error of
+/// `proof_required` if no proof is supplied, `403 Forbidden` if it is not valid
+/// for the user, and `404 Not Found` with an error of `user_not_found` if the
+/// user does not exist.
+#[get("/user/export?<username>&<proof>")]
+pub fn export(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    username: String,
+    proof: Option<String>,
+) -> Result<Json<UserExport>, ApiError> {
+    let user_id =
+        authorize(&conn, &challenges, session, proof.as_deref(), &username, Action::ExportUser)?;
+
+    // The archive is read in a transaction so that it is consistent.
+    let export = conn.0.transaction::<_, ApiError, _>(|| {
+        let user =
+            Users.find(user_id).select((UserID, UserPublicKey, Username)).first::<User>(&conn.0)?;
+
+        // ```sql
+        // SELECT Posts.*, Users.Username, Users.PublicKey FROM Posts
+        // INNER JOIN Users ON Users.ID = Posts.UserID
+        // WHERE Posts.UserID = {user_id}
+        //  OR Posts.ID IN (SELECT PostID FROM NOA WHERE UserID = {user_id})
+        // ORDER BY TimePosted DESC, Posts.ID DESC
+        // ```
+        let shared = NOA.filter(NOAUserID.eq(user_id)).select(NOAPostID);
+        let posts = Posts
+            .inner_join(Users.on(UserID.eq(PostUserID)))
+            .filter(PostUserID.eq(user_id).or(PostID.eq_any(shared)))
+            .order_by((TimePosted.desc(), PostID.desc()))
+            .select((
+                PostContent,
+                PostNonce,
+                Username,
+                UserPublicKey,
+                PostID,
+                TimePosted,
+                EncryptedPublicKey,
+                EncryptedPublicKeyNonce,
+            ))
+            .load::<PostResponse>(&conn.0)?;
+
+        // ```sql
+        // SELECT NOA.PostID, Users.Username, NOA.SecretKey, NOA.Nonce FROM NOA
+        // INNER JOIN Users ON Users.ID = NOA.UserID
+        // INNER JOIN Posts ON Posts.ID = NOA.PostID
+        // WHERE NOA.UserID = {user_id} OR Posts.UserID = {user_id}
+        // ORDER BY NOA.PostID, Users.Username
+        // ```
+        let noas = NOA
+            .inner_join(Users.on(UserID.eq(NOAUserID)))
+            .inner_join(Posts.on(PostID.eq(NOAPostID)))
+            .filter(NOAUserID.eq(user_id).or(PostUserID.eq(user_id)))
+            .order_by((NOAPostID, Username))
+            .select((NOAPostID, Username, SecretKey, SecretKeyNonce))
+            .load::<NoaExport>(&conn.0)?;
+
+        // ```sql
+        // SELECT Username FROM UsernameReservations
+        // WHERE UserID = {user_id} AND Expires > {now}
+        // ORDER BY Username
+        // ```
+        let reserved_usernames = UsernameReservations
+            .filter(ReservationUserID.eq(user_id))
+            .filter(Expires.gt(Utc::now().naive_utc()))
+            .order_by(ReservedUsername)
+            .select(ReservedUsername)
+            .load::<String>(&conn.0)?;
+
+        Ok(UserExport {
+            user,
+            exported_at: Utc::now().naive_utc(),
+            posts,
+            noas,
+            reserved_usernames,
+        })
+    })?;
+    return Ok(Json(export));
+}
+
+/// The `user/key` endpoint can be sent a PUT request with a body of
+/// `UserKeyData`, replacing the user's public key, such as when their secret
+/// key has leaked. As the content of each post the user authored and the post
+/// keys of their NOAs are encrypted under their key pair, the request must
+/// include every post the user authored and every NOA in their `user/export`
+/// archive, re-encrypted under the new key. These are replaced along with the
+/// public key in a single transaction, and any outstanding authentication token
+/// sealed to the previous key is discarded.
+///
+/// The user is identified by `proof`, made with their previous key. Sessions
+/// are not accepted, as one taken with the leaked key could otherwise replace
+/// the key again, and every session issued before the key was replaced ends.
+///
+/// It responds `200 OK` on success, `400 Bad Request` with an error of
+/// `invalid_public_key` if the new public key is not valid, `401 Unauthorized`
+/// with an error of `proof_required` if no proof is supplied, `403 Forbidden`
+/// if it is not valid for the user, `404 Not Found` with an error of
+/// `user_not_found` if the user does not exist, and `409 Conflict` with an
+/// error of `conflict` if the posts or NOAs supplied are not exactly those the
+/// user holds, such as when a post was shared with them since they were
+/// exported, or if the new public key is already registered.
+#[put("/user/key", data = "<key_data>")]
+pub fn put_key(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    key_data: Json<UserKeyData>,
+) -> Result<Status, ApiError> {
+    let key_data = key_data.into_inner();
+
+    validate_public_key(&key_data.public_key)?;
+    let user_id = authorize(
+        &conn,
+        &challenges,
+        session,
+        key_data.proof.as_deref(),
+        &key_data.username,
+        Action::RotateKey,
+    )?;
+
+    conn.0.transaction::<_, ApiError, _>(|| {
+        let public_key = Users.find(user_id).select(UserPublicKey).first::<String>(&conn.0)?;
+
+        // Nothing may be left encrypted under the previous key, so the posts
+        // and NOAs supplied must be exactly those held.
+        // ```sql
+        // SELECT ID FROM Posts WHERE UserID = {user_id} ORDER BY ID
+        // ```
+        let authored = Posts
+            .filter(PostUserID.eq(user_id))
+            .select(PostID)
+            .order_by(PostID)
+            .load::<i32>(&conn.0)?;
+        let mut supplied = key_data.posts.iter().map(|post| post.post_id).collect::<Vec<_>>();
+        supplied.sort();
+        if supplied != authored {
+            return Err(ApiError::Conflict);
+        }
+
+        // Readers are compared by their username keys, as usernames are looked
+        // up. Both sides are sorted here rather than by the query, as the
+        // database may collate them differently.
+        // ```sql
+        // SELECT NOA.PostID, Users.UsernameKey FROM NOA
+        // INNER JOIN Users ON Users.ID = NOA.UserID
+        // INNER JOIN Posts ON Posts.ID = NOA.PostID
+        // WHERE NOA.UserID = {user_id} OR Posts.UserID = {user_id}
+        // ```
+        let mut noas = NOA
+            .inner_join(Users.on(UserID.eq(NOAUserID)))
+            .inner_join(Posts.on(PostID.eq(NOAPostID)))
+            .filter(NOAUserID.eq(user_id).or(PostUserID.eq(user_id)))
+            .select((NOAPostID, UsernameKey))
+            .load::<(i32, String)>(&conn.0)?;
+        noas.sort();
+        let mut supplied = key_data
+            .noas
+            .iter()
+            .map(|noa| (noa.post_id, username::key(&noa.username)))
+            .collect::<Vec<_>>();
+        supplied.sort();
+        if supplied != noas {
+            return Err(ApiError::Conflict);
+        }
+
+        // ```sql
+        // UPDATE Users SET PublicKey = {key_data.public_key} WHERE ID = {user_id};
+        // DELETE FROM Auth WHERE PublicKey = {public_key};
+        // ```
+        diesel::update(Users.find(user_id))
+            .set(UserPublicKey.eq(&key_data.public_key))
+            .execute(&conn.0)?;
+        diesel::delete(Auth.filter(AuthPublicKey.eq(&public_key))).execute(&conn.0)?;
+
+        for post in &key_data.posts {
+            // ```sql
+            // UPDATE Posts
+            // SET Content = {post.content}, Nonce = {post.nonce},
+            //  PublicKey = {post.public_key}, PublicKeyNonce = {post.public_key_nonce}
+            // WHERE ID = {post.post_id}
+            // ```
+            diesel::update(Posts.find(post.post_id))
+                .set((
+                    PostContent.eq(&post.content),
+                    PostNonce.eq(&post.nonce),
+                    EncryptedPublicKey.eq(&post.public_key),
+                    EncryptedPublicKeyNonce.eq(&post.public_key_nonce),
+                ))
+                .execute(&conn.0)?;
+        }
+
+        for noa in &key_data.noas {
+            // ```sql
+            // UPDATE NOA SET SecretKey = {noa.encrypted_secret_key}, Nonce = {noa.nonce}
+            // WHERE PostID = {noa.post_id}
+            //  AND UserID IN
+            //   (SELECT ID FROM Users WHERE UsernameKey = {username::key(noa.username)})
+            // ```
+            let reader = Users.filter(UsernameKey.eq(username::key(&noa.username))).select(UserID);
+            diesel::update(NOA.filter(NOAPostID.eq(noa.post_id)).filter(NOAUserID.eq_any(reader)))
+                .set((SecretKey.eq(&noa.encrypted_secret_key), SecretKeyNonce.eq(&noa.nonce)))
+                .execute(&conn.0)?;
+        }
+        Ok(())
+    })?;
+    return Ok(Status::Ok);
+}
+
+/// The `user/username` endpoint can be sent a PUT request with a body of
+///
+/// ```json
+/// {
+///     username: "...",
+///     proof: "...",
+///     newUsername: "..."
+/// }
+/// ```
+///
+/// renaming the user to `newUsername`, while keeping their ID and keys. The new
+/// username is validated and normalised as at registration. Their previous
+/// username is then reserved for them for the period set by the
+/// `username_reservation_days` configuration key, 30 days by default, during
+/// which no one else may take it, and lookups by it redirect to the new
+/// username. A user may rename back to a username reserved for them, or to
+/// their own username in a different case, which reserves nothing.
+///
+/// The user is identified by `proof`, as a session is not accepted for
+/// renaming the account. It responds `200 OK` on success, `400 Bad Request`
+/// with an error of `invalid_username` if the new username is not valid, `401
+/// Unauthorized` with an error of `proof_required` if no proof is supplied,
+/// `403 Forbidden` if it is not valid for the user, `404 Not Found` with an
+/// error of `user_not_found` if the user does not exist, and `409 Conflict`
+/// with an error of `username_taken` if the new username, ignoring case, is in
+/// use or reserved for another user.
+#[put("/user/username", data = "<username_data>")]
+pub fn put_username(
+    conn: CoreDbConn,
+    session: Result<Session, ApiError>,
+    challenges: State<Challenges>,
+    username_config: State<UsernameConfig>,
+    username_data: Json<UsernameData>,
+) -> Result<Status, ApiError> {
+    let username_data = username_data.into_inner();
+
+    let (new_username, new_key) = username::validate(&username_data.new_username)?;
+    let user_id = authorize(
+        &conn,
+        &challenges,
+        session,
+        username_data.proof.as_deref(),
+        &username_data.username,
+        Action::RenameUser,
+    )?;
+    let old_username = Users.find(user_id).select(Username).first::<String>(&conn.0)?;
+    if new_username == old_username {
+        return Ok(Status::Ok);
+    }
+    let old_key = username::key(&old_username);
+    if new_key == old_key {
+        // Only the case or normalisation of the username changes, so it is
+        // still the user's own and there is nothing to reserve.
+        // ```sql
+        // UPDATE Users SET Username = {new_username} WHERE ID = {user_id}
+        // ```
+        diesel::update(Users.find(user_id)).set(Username.eq(&new_username)).execute(&conn.0)?;
+        return Ok(Status::Ok);
+    }
+
+    conn.0.transaction::<_, ApiError, _>(|| {
+        // SELECT ID FROM Users WHERE UsernameKey = {new_key}
+        let holder = Users
+            .filter(UsernameKey.eq(&new_key))
+            .select(UserID)
+            .first::<i32>(&conn.0)
+            .optional()?;
+        let reserved_for = reserved_for(&conn, &new_key)?;
+        if holder.or(reserved_for).map_or(false, |holder| holder != user_id) {
+            return Err(ApiError::UsernameTaken);
+        }
+
+        // Any reservation of either username is replaced, whether it is the
+        // user's own or has expired.
+        // ```sql
+        // DELETE FROM UsernameReservations WHERE Username IN ({old_key}, {new_key});
+        // UPDATE Users SET Username = {new_username}, UsernameKey = {new_key}
+        // WHERE ID = {user_id};
+        // INSERT INTO UsernameReservations VALUES ({old_key}, {user_id}, {now + reservation});
+        // ```
+        diesel::delete(UsernameReservations.filter(ReservedUsername.eq_any(&[&old_key, &new_key])))
+            .execute(&conn.0)?;
+        diesel::update(Users.find(user_id))
+            .set((Username.eq(&new_username), UsernameKey.eq(&new_key)))
+            .execute(&conn.0)
+            .map_err(|e| username_conflict(&conn, &new_key, e))?;
+        diesel::insert_into(UsernameReservations)
+            .values(&UsernameReservationInsert {
+                username: &old_key,
+                user_id,
+                expires: Utc::now().naive_utc() + username_config.reservation,
+            })
+            .execute(&conn.0)?;
+        Ok(())
+    })?;
+    return Ok(Status::Ok);
+}
+
+/// Returns the ID of the user the username with the key `username_key` is
+/// reserved for, if it is reserved.
+fn reserved_for(conn: &CoreDbConn, username_key: &str) -> Result<Option<i32>, ApiError> {
+    // ```sql
+    // SELECT UserID FROM UsernameReservations
+    // WHERE Username = {username_key} AND Expires > {now}
+    // LIMIT 1
+    // ```
+    Ok(UsernameReservations
+        .filter(ReservedUsername.eq(username_key))
+        .filter(Expires.gt(Utc::now().naive_utc()))
+        .select(ReservationUserID)
+        .first::<i32>(&conn.0)
+        .optional()?)
+}
+
+/// Checks that `public_key` is the base64 encoding of a `box_` public key.
+fn validate_public_key(public_key: &str) -> Result<(), ApiError> {
+    match base64::decode(public_key) {
+        Ok(ref public_key) if public_key.len() == pkc::PUBLICKEYBYTES => Ok(()),
+        _ => Err(ApiError::InvalidPublicKey),
+    }
+}
+
+/// Converts the failure of a query writing the username with the key
+/// `username_key`, mapping a violation of uniqueness to `UsernameTaken` if a
+/// user now holds that username. As the database does not report which
+/// constraint was violated in the same way on every backend, this is checked by
+/// querying the username, and other violations, such as of the uniqueness of
+/// the public key, remain a `Conflict`.
+fn username_conflict(conn: &CoreDbConn, username_key: &str, error: DieselError) -> ApiError {
+    match error {
+        DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+            // SELECT ID FROM Users WHERE UsernameKey = {username_key}
+            let holder = Users
+                .filter(UsernameKey.eq(username_key))
+                .select(UserID)
+                .first::<i32>(&conn.0)
+                .optional();
+            match holder {
+                Ok(Some(_)) => ApiError::UsernameTaken,
+                Ok(None) => ApiError::Conflict,
+                Err(e) => e.into(),
+            }
+        },
+        error => error.into(),
     }
 }
@@ -7,12 +7,15 @@ table! {
         ID -> Integer,
         PublicKey -> Text,
         Username -> Text,
+        UsernameKey -> Text,
     }
 }
 
 table! {
-    Auth (PublicKey) {
+    Auth (ID) {
+        ID -> Integer,
         PublicKey -> Text,
+        Action -> Text,
         ExpectedToken -> Text,
         Timeout -> Timestamp,
     }
@@ -39,5 +42,24 @@ table! {
     }
 }
 
+table! {
+    PendingRegistrations (UsernameKey, PublicKey) {
+        PublicKey -> Text,
+        Username -> Text,
+        UsernameKey -> Text,
+        ExpectedToken -> Text,
+        Timeout -> Timestamp,
+    }
+}
+
+table! {
+    UsernameReservations (Username) {
+        Username -> Text,
+        UserID -> Integer,
+        Expires -> Timestamp,
+    }
+}
+
 allow_tables_to_appear_in_same_query!(Users, Auth);
+allow_tables_to_appear_in_same_query!(Users, UsernameReservations);
 allow_tables_to_appear_in_same_query!(Users, NOA, Posts);
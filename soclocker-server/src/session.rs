@@ -0,0 +1,161 @@
+//! This module contains the session tokens issued by the `auth` endpoint, which
+//! allow a single proof of identity to authorise many requests.
+//!
+//! A session token is the base64 encoding of the user's ID, the token's expiry
+//! time and the user's public key, followed by a tag authenticating them under
+//! the server's session key. Tokens are not stored by the server, but are only
+//! accepted while the user holds the public key they were issued for, so
+//! rotating the key, or deleting the account, ends every session. They are sent
+//! as `Authorization: Bearer <token>`.
+//!
+//! Sessions only authorise the actions listed by `Action::allows_session`.
+//! Actions which destroy data or change the account require a fresh proof of
+//! identity, so that a stolen session cannot take over the account.
+
+use crate::{error::ApiError, TIMEOUT_SECONDS};
+use chrono::{Duration, NaiveDateTime, Utc};
+use rocket::{
+    fairing::{AdHoc, Fairing},
+    http::Status,
+    request::{self, FromRequest},
+    Outcome,
+    Request,
+    State,
+};
+use sodiumoxide::crypto::{auth, box_ as pkc};
+use std::convert::TryInto;
+
+/// The Rocket configuration key containing the base64 encoded session key.
+/// This should be set when several servers share a database, so that each
+/// accepts the sessions issued by the others. Otherwise a new session key is
+/// generated at launch, ending every existing session.
+pub const SESSION_KEY_CONFIG: &str = "session_key";
+
+/// The length of the claims of a session token: a 4 byte user ID, an 8 byte
+/// expiry time and the user's public key.
+const CLAIMS_BYTES: usize = 4 + 8 + pkc::PUBLICKEYBYTES;
+
+/// The length of a session token before it is base64 encoded: its claims and
+/// the authentication tag.
+const TOKEN_BYTES: usize = CLAIMS_BYTES + auth::TAGBYTES;
+
+/// The key session tokens are authenticated with.
+#[derive(Debug)]
+pub struct SessionKey(auth::Key);
+
+impl SessionKey {
+    /// Constructs a fairing which loads the session key from the Rocket
+    /// configuration on attach, or generates one if none is configured, and
+    /// manages it for use by the routes. Launch is aborted if the configured
+    /// key is not valid.
+    pub fn fairing() -> impl Fairing {
+        AdHoc::on_attach("Session Key", |rocket| {
+            let key = match rocket.config().get_str(SESSION_KEY_CONFIG) {
+                Ok(key) => base64::decode(key).ok().and_then(|key| auth::Key::from_slice(&key)),
+                Err(_) => Some(auth::gen_key()),
+            };
+            match key {
+                Some(key) => Ok(rocket.manage(SessionKey(key))),
+                None => {
+                    rocket::logger::error("The session key is not a valid base64 encoded key.");
+                    Err(rocket)
+                },
+            }
+        })
+    }
+
+    /// Issues a session token for the user with the ID `user_id` and the
+    /// base64 encoded `public_key`, returning the token and the time it
+    /// expires.
+    pub fn issue(
+        &self,
+        user_id: i32,
+        public_key: &str,
+        now: NaiveDateTime,
+    ) -> Result<(String, NaiveDateTime), ApiError> {
+        let public_key = decode_public_key(public_key).ok_or(ApiError::Internal)?;
+        let expires = now + Duration::seconds(TIMEOUT_SECONDS);
+        let mut token = Vec::with_capacity(TOKEN_BYTES);
+        token.extend_from_slice(&user_id.to_be_bytes());
+        token.extend_from_slice(&expires.timestamp().to_be_bytes());
+        token.extend_from_slice(&public_key.0);
+        let tag = auth::authenticate(&token, &self.0);
+        token.extend_from_slice(&tag.0);
+        Ok((base64::encode(&token), expires))
+    }
+
+    /// Checks that `token` was issued by this server and has not expired at
+    /// `now`, returning the session it represents.
+    pub fn verify(&self, token: &str, now: NaiveDateTime) -> Result<Session, ApiError> {
+        let token = base64::decode(token).map_err(|_| ApiError::AuthInvalid)?;
+        if token.len() != TOKEN_BYTES {
+            return Err(ApiError::AuthInvalid);
+        }
+        let (claims, tag) = token.split_at(CLAIMS_BYTES);
+        let tag = auth::Tag::from_slice(tag).ok_or(ApiError::AuthInvalid)?;
+        if !auth::verify(&tag, claims, &self.0) {
+            return Err(ApiError::AuthInvalid);
+        }
+        let user_id = i32::from_be_bytes(claims[..4].try_into().unwrap());
+        let expires = i64::from_be_bytes(claims[4..12].try_into().unwrap());
+        let public_key = pkc::PublicKey::from_slice(&claims[12..]).ok_or(ApiError::AuthInvalid)?;
+        if expires < now.timestamp() {
+            return Err(ApiError::AuthExpired);
+        }
+        Ok(Session { user_id, public_key, expires: NaiveDateTime::from_timestamp(expires, 0) })
+    }
+}
+
+/// Decodes the base64 encoded `public_key` of a user.
+fn decode_public_key(public_key: &str) -> Option<pkc::PublicKey> {
+    base64::decode(public_key).ok().and_then(|public_key| pkc::PublicKey::from_slice(&public_key))
+}
+
+/// A valid session, taken from the `Authorization` header of a request.
+///
+/// As a request guard this fails with `auth_required` if the header is
+/// missing, so routes which also accept a proof of identity should take a
+/// `Result<Session, ApiError>` and only use it when no proof was supplied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    /// The ID of the user the session was issued to.
+    pub user_id: i32,
+
+    /// The public key the user held when the session was issued.
+    pub public_key: pkc::PublicKey,
+
+    /// The time the session expires.
+    pub expires: NaiveDateTime,
+}
+
+impl Session {
+    /// Whether the session belongs to the user with the ID `user_id` and the
+    /// base64 encoded `public_key`.
+    pub fn belongs_to(&self, user_id: i32, public_key: &str) -> bool {
+        self.user_id == user_id && decode_public_key(public_key) == Some(self.public_key)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Session {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Session, ApiError> {
+        let key = match request.guard::<State<SessionKey>>() {
+            Outcome::Success(key) => key,
+            _ => return Outcome::Failure((Status::InternalServerError, ApiError::Internal)),
+        };
+        let token = match request.headers().get_one("Authorization") {
+            Some(header) => {
+                match header.strip_prefix("Bearer ") {
+                    Some(token) => token.trim(),
+                    None => return Outcome::Failure((Status::Forbidden, ApiError::AuthInvalid)),
+                }
+            },
+            None => return Outcome::Failure((Status::Unauthorized, ApiError::AuthRequired)),
+        };
+        match key.verify(token, Utc::now().naive_utc()) {
+            Ok(session) => Outcome::Success(session),
+            Err(e) => Outcome::Failure((e.status(), e)),
+        }
+    }
+}
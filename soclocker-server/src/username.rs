@@ -0,0 +1,38 @@
+//! This module contains the validation and normalisation of usernames.
+//!
+//! Usernames are stored in Unicode Normalisation Form C, as given at
+//! registration. Each is also stored with its key, its compatibility case
+//! folded form, which must be unique, so that no two users have names which
+//! differ only in case or in compatibility characters such as ligatures.
+
+use crate::error::ApiError;
+use caseless::Caseless;
+use unicode_normalization::UnicodeNormalization;
+
+/// The most characters a username or its key may have, matching the size of
+/// the `Username` and `UsernameKey` columns.
+pub const MAX_USERNAME_CHARS: usize = 100;
+
+/// Validates `username`, returning its normalised form and its key. A username
+/// must be between 1 and `MAX_USERNAME_CHARS` letters, digits, `.`, `-` or `_`.
+pub fn validate(username: &str) -> Result<(String, String), ApiError> {
+    let username = username.nfc().collect::<String>();
+    let length = username.chars().count();
+    if length < 1 || length > MAX_USERNAME_CHARS {
+        return Err(ApiError::InvalidUsername);
+    }
+    if !username.chars().all(|c| c.is_alphanumeric() || c == '.' || c == '-' || c == '_') {
+        return Err(ApiError::InvalidUsername);
+    }
+    let key = key(&username);
+    if key.chars().count() > MAX_USERNAME_CHARS {
+        return Err(ApiError::InvalidUsername);
+    }
+    Ok((username, key))
+}
+
+/// Returns the key of `username`, which is equal for usernames which match
+/// under Unicode compatibility caseless matching.
+pub fn key(username: &str) -> String {
+    username.nfd().default_case_fold().nfkd().default_case_fold().nfkc().collect()
+}
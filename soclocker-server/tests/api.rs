@@ -0,0 +1,1586 @@
+//! Integration tests which drive every route through Rocket's local client,
+//! performing the full protocol with real cryptography against a throwaway
+//! SQLite database. Run with `cargo test --no-default-features --features
+//! sqlite`.
+#![cfg(feature = "sqlite")]
+
+use diesel::{Connection, QueryDsl, RunQueryDsl, SqliteConnection};
+use rocket::{
+    config::{Config, Environment, LoggingLevel, Value},
+    http::{ContentType, Header, Status},
+    local::{Client, LocalRequest, LocalResponse},
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use soclocker_server::{
+    build_rocket,
+    maintenance::{self, PurgeCounts},
+    models::{
+        AuthResponse,
+        ErrorResponse,
+        NoaOuterResponse,
+        NoaResponse,
+        PostCreatedResponse,
+        PowChallenge,
+        ServerPublicKeysResponse,
+        SessionResponse,
+        User,
+        UserExport,
+    },
+    schema,
+    ServerOptions,
+};
+use sodiumoxide::crypto::{box_ as pkc, hash::sha512};
+use std::{
+    collections::HashMap,
+    env,
+    fs,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Distinguishes the databases of tests running concurrently.
+static DATABASE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A server running against its own throwaway database, which is removed when
+/// the server is dropped.
+struct TestServer {
+    client: Client,
+    database: PathBuf,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) { fs::remove_file(&self.database).ok(); }
+}
+
+/// A registered user, holding their secret key as the client would.
+#[derive(Clone)]
+struct TestUser {
+    username: String,
+    public: pkc::PublicKey,
+    secret: pkc::SecretKey,
+}
+
+impl TestServer {
+    /// Builds the server with a newly generated key and an empty database.
+    fn new() -> TestServer { TestServer::with_options(&ServerOptions::default()) }
+
+    /// Builds the server as `new` does, assembled according to `options`.
+    fn with_options(options: &ServerOptions) -> TestServer {
+        TestServer::with_config(options, json!({}))
+    }
+
+    /// Builds the server as `with_options` does, adding the configuration
+    /// tables given as JSON. Unless given, routes are not rate limited.
+    fn with_config(options: &ServerOptions, tables: serde_json::Value) -> TestServer {
+        sodiumoxide::init().unwrap();
+        let database = env::temp_dir().join(format!(
+            "soclocker-test-{}-{}.sqlite",
+            process::id(),
+            DATABASE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut database_config = HashMap::new();
+        database_config.insert("url", Value::from(database.to_str().unwrap()));
+        let mut databases = HashMap::new();
+        databases.insert("core_db", Value::from(database_config));
+        let (_, server_secret) = pkc::gen_keypair();
+        let unlimited = json!({ "per_ip": 0, "per_username": 0 });
+        let mut config = Config::build(Environment::Development)
+            .log_level(LoggingLevel::Critical)
+            .extra("databases", databases)
+            .extra("server_secret_key", base64::encode(&server_secret.0))
+            .extra(
+                "rate_limits",
+                Value::try_from(json!({ "auth": unlimited, "register": unlimited })).unwrap(),
+            );
+        for (key, table) in tables.as_object().unwrap() {
+            config = config.extra(key, Value::try_from(table).unwrap());
+        }
+        let config = config.finalize().unwrap();
+        let client = Client::new(build_rocket(config, options)).unwrap();
+        TestServer { client, database }
+    }
+
+    /// Sends a GET request, returning the status and body.
+    fn get(&self, uri: &str) -> (Status, String) {
+        let mut response = self.client.get(uri.to_string()).dispatch();
+        (response.status(), response.body_string().unwrap_or_default())
+    }
+
+    /// Sends a GET request authorised by `session`, returning the status and
+    /// body.
+    fn get_with_session(&self, uri: &str, session: &str) -> (Status, String) {
+        let mut response = self
+            .client
+            .get(uri.to_string())
+            .header(Header::new("Authorization", format!("Bearer {}", session)))
+            .dispatch();
+        (response.status(), response.body_string().unwrap_or_default())
+    }
+
+    /// Sends a DELETE request, returning the status and body.
+    fn delete(&self, uri: &str) -> (Status, String) {
+        let mut response = self.client.delete(uri.to_string()).dispatch();
+        (response.status(), response.body_string().unwrap_or_default())
+    }
+
+    /// Sends a DELETE request authorised by `session`, returning the status and
+    /// body.
+    fn delete_with_session(&self, uri: &str, session: &str) -> (Status, String) {
+        let mut response = self
+            .client
+            .delete(uri.to_string())
+            .header(Header::new("Authorization", format!("Bearer {}", session)))
+            .dispatch();
+        (response.status(), response.body_string().unwrap_or_default())
+    }
+
+    /// Sends a GET request, deserializing the successful response.
+    fn get_json<T: DeserializeOwned>(&self, uri: &str) -> T {
+        let (status, body) = self.get(uri);
+        assert_eq!(status, Status::Ok, "GET {} failed: {}", uri, body);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    /// Sends a POST request with a JSON body, returning the status and body.
+    fn post(&self, uri: &str, body: serde_json::Value) -> (Status, String) {
+        send(self.client.post(uri.to_string()), None, body)
+    }
+
+    /// Sends a PUT request with a JSON body, returning the status and body.
+    fn put(&self, uri: &str, body: serde_json::Value) -> (Status, String) {
+        send(self.client.put(uri.to_string()), None, body)
+    }
+
+    /// Sends a POST request as `post` does, authorised by `session`.
+    fn post_with_session(
+        &self,
+        uri: &str,
+        session: &str,
+        body: serde_json::Value,
+    ) -> (Status, String) {
+        send(self.client.post(uri.to_string()), Some(session), body)
+    }
+
+    /// Sends a PUT request as `put` does, authorised by `session`.
+    fn put_with_session(
+        &self,
+        uri: &str,
+        session: &str,
+        body: serde_json::Value,
+    ) -> (Status, String) {
+        send(self.client.put(uri.to_string()), Some(session), body)
+    }
+
+    /// Runs `sql` directly against the server's database.
+    fn execute_sql(&self, sql: &str) {
+        let conn = SqliteConnection::establish(self.database.to_str().unwrap()).unwrap();
+        diesel::sql_query(sql).execute(&conn).unwrap();
+    }
+
+    /// Registers a new user with a newly generated keypair, proving possession
+    /// of its secret key.
+    fn register(&self, username: &str) -> TestUser {
+        let (public, secret) = pkc::gen_keypair();
+        self.register_user(TestUser { username: username.to_string(), public, secret })
+    }
+
+    /// Registers `user` with their existing keypair, as `register` does.
+    fn register_user(&self, user: TestUser) -> TestUser {
+        let (status, body) = self.post(
+            "/_/user",
+            json!({ "publicKey": base64::encode(&user.public.0), "username": user.username }),
+        );
+        assert_eq!(status, Status::Ok);
+        let token = self.open_token(&serde_json::from_str(&body).unwrap(), &user);
+        let (status, _) = self
+            .post("/_/user/confirm", json!({ "username": user.username, "decryptedToken": token }));
+        assert_eq!(status, Status::Created);
+        user
+    }
+
+    /// Requests an authentication token for `user` to perform `action` and
+    /// decrypts it, returning the base64 encoded proof of identity.
+    fn proof(&self, user: &TestUser, action: &str) -> String {
+        let auth: AuthResponse =
+            self.get_json(&format!("/_/auth?username={}&action={}", user.username, action));
+        self.open_token(&auth, user)
+    }
+
+    /// Decrypts a token sealed to `user` by the server, returning it base64
+    /// encoded.
+    fn open_token(&self, auth: &AuthResponse, user: &TestUser) -> String {
+        let server_public = self.server_public_key(auth.key_id);
+        let token = pkc::open(
+            &base64::decode(&auth.encrypted_token).unwrap(),
+            &pkc::Nonce::from_slice(&base64::decode(&auth.nonce).unwrap()).unwrap(),
+            &server_public,
+            &user.secret,
+        )
+        .expect("token should be sealed to the user");
+        base64::encode(&token)
+    }
+
+    /// Proves the identity of `user`, returning a newly issued session token.
+    fn session(&self, user: &TestUser) -> String {
+        let (status, body) = self.post(
+            "/_/auth",
+            json!({
+                "decryptedToken": self.proof(user, "login"),
+                "username": user.username,
+                "session": true,
+            }),
+        );
+        assert_eq!(status, Status::Ok);
+        serde_json::from_str::<SessionResponse>(&body).unwrap().session
+    }
+
+    /// Renames `user` to `new_username`, proving their identity, returning the
+    /// status and body.
+    fn rename(&self, user: &TestUser, new_username: &str) -> (Status, String) {
+        self.put(
+            "/_/user/username",
+            json!({
+                "username": user.username,
+                "proof": self.proof(user, "rename_user"),
+                "newUsername": new_username,
+            }),
+        )
+    }
+
+    /// Fetches the published server public key with the ID `key_id`.
+    fn server_public_key(&self, key_id: u32) -> pkc::PublicKey {
+        let keys: ServerPublicKeysResponse = self.get_json("/_/server_public_key");
+        let key = keys.keys.into_iter().find(|key| key.id == key_id).unwrap();
+        pkc::PublicKey::from_slice(&base64::decode(&key.public_key).unwrap()).unwrap()
+    }
+
+    /// Creates a post by `author` containing `content`, readable by `readers`,
+    /// encrypting it as the client does.
+    fn create_post(
+        &self,
+        author: &TestUser,
+        content: &str,
+        readers: &[&TestUser],
+    ) -> PostCreatedResponse {
+        let mut body = encrypt_post(author, content, readers);
+        body["username"] = json!(author.username);
+        body["proof"] = json!(self.proof(author, "create_post"));
+        let (status, body) = self.post("/_/post", body);
+        assert_eq!(status, Status::Ok);
+        serde_json::from_str(&body).unwrap()
+    }
+
+    /// Fetches the first page of posts shared with `reader`.
+    fn noa(&self, reader: &TestUser) -> NoaOuterResponse {
+        let uri = format!("/_/noa?username={}", reader.username);
+        let (status, body) = self.get_with_session(&uri, &self.session(reader));
+        assert_eq!(status, Status::Ok, "GET {} failed: {}", uri, body);
+        serde_json::from_str(&body).unwrap()
+    }
+}
+
+/// Encrypts `content` under a new post key as `author`, as the client does,
+/// returning the `content`, `nonce`, `publicKey`, `publicKeyNonce` and
+/// `noaEncryptedKeys` granting `readers` access.
+fn encrypt_post(author: &TestUser, content: &str, readers: &[&TestUser]) -> serde_json::Value {
+    let (post_public, post_secret) = pkc::gen_keypair();
+    let content_nonce = pkc::gen_nonce();
+    let content = pkc::seal(content.as_bytes(), &content_nonce, &post_public, &author.secret);
+    let public_key_nonce = pkc::gen_nonce();
+    let public_key = pkc::seal(&post_public.0, &public_key_nonce, &author.public, &author.secret);
+    let noa_encrypted_keys = readers
+        .iter()
+        .map(|reader| {
+            let nonce = pkc::gen_nonce();
+            json!({
+                "username": reader.username,
+                "encryptedSecretKey": base64::encode(
+                    &pkc::seal(&post_secret.0, &nonce, &reader.public, &author.secret)
+                ),
+                "nonce": base64::encode(&nonce),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "content": base64::encode(&content),
+        "nonce": base64::encode(&content_nonce),
+        "publicKey": base64::encode(&public_key),
+        "publicKeyNonce": base64::encode(&public_key_nonce),
+        "noaEncryptedKeys": noa_encrypted_keys,
+    })
+}
+
+/// Searches for a solution to a proof of work challenge, returning it as sent
+/// in the `X-Proof-Of-Work` header.
+fn solve(challenge: &PowChallenge) -> String {
+    (0u64..)
+        .map(|nonce| format!("{}:{}", challenge.salt, nonce))
+        .find(|solution| leading_zero_bits(solution) >= u32::from(challenge.difficulty))
+        .unwrap()
+}
+
+/// Counts the zero bits at the start of the SHA-512 digest of `solution`.
+fn leading_zero_bits(solution: &str) -> u32 {
+    let digest = sha512::hash(solution.as_bytes());
+    let zero_bytes = digest.0.iter().take_while(|&&byte| byte == 0).count();
+    zero_bytes as u32 * 8 + digest.0.get(zero_bytes).map_or(0, |byte| byte.leading_zeros())
+}
+
+/// Decodes a base64 encoded nonce.
+fn nonce(value: &str) -> pkc::Nonce {
+    pkc::Nonce::from_slice(&base64::decode(value).unwrap()).unwrap()
+}
+
+/// Builds the body of a request replacing the key pair of `user` with that of
+/// `replacement`, re-encrypting everything in `export` as the client does.
+/// Every other user sharing posts with `user` must be among `others`.
+fn rotate_key(
+    export: &UserExport,
+    user: &TestUser,
+    replacement: &TestUser,
+    others: &[&TestUser],
+) -> serde_json::Value {
+    let posts = export
+        .posts
+        .iter()
+        .filter(|post| post.username == user.username)
+        .map(|post| {
+            let post_public = pkc::open(
+                &base64::decode(&post.encrypted_public_key).unwrap(),
+                &nonce(&post.encrypted_public_key_nonce),
+                &user.public,
+                &user.secret,
+            )
+            .unwrap();
+            let post_public = pkc::PublicKey::from_slice(&post_public).unwrap();
+            let content = pkc::open(
+                &base64::decode(&post.encrypted_content).unwrap(),
+                &nonce(&post.nonce),
+                &post_public,
+                &user.secret,
+            )
+            .unwrap();
+            let (content_nonce, public_key_nonce) = (pkc::gen_nonce(), pkc::gen_nonce());
+            json!({
+                "postId": post.post_id,
+                "content": base64::encode(
+                    &pkc::seal(&content, &content_nonce, &post_public, &replacement.secret)
+                ),
+                "nonce": base64::encode(&content_nonce),
+                "publicKey": base64::encode(&pkc::seal(
+                    &post_public.0,
+                    &public_key_nonce,
+                    &replacement.public,
+                    &replacement.secret
+                )),
+                "publicKeyNonce": base64::encode(&public_key_nonce),
+            })
+        })
+        .collect::<Vec<_>>();
+    let noas = export
+        .noas
+        .iter()
+        .map(|noa| {
+            // Each post key is sealed between the author and the reader, one
+            // of whom is the user, so is re-sealed with the other's key.
+            let post = export.posts.iter().find(|post| post.post_id == noa.post_id).unwrap();
+            let peer = if post.username == user.username { &noa.username } else { &post.username };
+            let (peer, new_peer) = if *peer == user.username {
+                (user.public, replacement.public)
+            } else {
+                let other = others.iter().find(|other| other.username == *peer).unwrap();
+                (other.public, other.public)
+            };
+            let post_secret = pkc::open(
+                &base64::decode(&noa.encrypted_secret_key).unwrap(),
+                &nonce(&noa.nonce),
+                &peer,
+                &user.secret,
+            )
+            .unwrap();
+            let secret_nonce = pkc::gen_nonce();
+            json!({
+                "postId": noa.post_id,
+                "username": noa.username,
+                "encryptedSecretKey": base64::encode(
+                    &pkc::seal(&post_secret, &secret_nonce, &new_peer, &replacement.secret)
+                ),
+                "nonce": base64::encode(&secret_nonce),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "username": user.username,
+        "publicKey": base64::encode(&replacement.public.0),
+        "posts": posts,
+        "noas": noas,
+    })
+}
+
+/// Dispatches `request` with a JSON body and an optional session, returning the
+/// status and body.
+fn send(request: LocalRequest, session: Option<&str>, body: serde_json::Value) -> (Status, String) {
+    let mut request = request.header(ContentType::JSON).body(body.to_string());
+    if let Some(session) = session {
+        request.add_header(Header::new("Authorization", format!("Bearer {}", session)));
+    }
+    let mut response = request.dispatch();
+    (response.status(), response.body_string().unwrap_or_default())
+}
+
+/// Percent encodes a base64 string for use in a query string.
+fn urlencode(value: &str) -> String {
+    value.replace('+', "%2B").replace('/', "%2F").replace('=', "%3D")
+}
+
+/// Extracts the error code from the body of a failed request.
+fn error_code(body: &str) -> String {
+    serde_json::from_str::<ErrorResponse>(body).expect("body should be an error").error
+}
+
+/// Decrypts the content of a post shared with `reader`, as the client does.
+fn decrypt_post(noa: &NoaResponse, author: &TestUser, reader: &TestUser) -> Option<String> {
+    let post_secret = pkc::open(
+        &base64::decode(&noa.encrypted_secret_key).ok()?,
+        &pkc::Nonce::from_slice(&base64::decode(&noa.nonce).ok()?)?,
+        &author.public,
+        &reader.secret,
+    )
+    .ok()?;
+    let content = pkc::open(
+        &base64::decode(&noa.post.encrypted_content).ok()?,
+        &pkc::Nonce::from_slice(&base64::decode(&noa.post.nonce).ok()?)?,
+        &author.public,
+        &pkc::SecretKey::from_slice(&post_secret)?,
+    )
+    .ok()?;
+    String::from_utf8(content).ok()
+}
+
+#[test]
+fn server_public_key_lists_active_key() {
+    let server = TestServer::new();
+    let keys: ServerPublicKeysResponse = server.get_json("/_/server_public_key");
+    assert_eq!(keys.keys.len(), 1);
+    assert_eq!(keys.keys[0].id, keys.active_key_id);
+}
+
+#[test]
+fn api_can_be_mounted_under_another_base() {
+    let server =
+        TestServer::with_options(&ServerOptions { api_base: "/api".to_string(), static_dir: None });
+    assert_eq!(server.get("/api/server_public_key").0, Status::Ok);
+    assert_eq!(server.get("/_/server_public_key").0, Status::NotFound);
+}
+
+#[test]
+fn registered_user_can_be_looked_up() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+
+    let user: User = server.get_json("/_/user?username=alice");
+    assert_eq!(user.username, "alice");
+    assert_eq!(user.public_key, base64::encode(&alice.public.0));
+
+    assert_eq!(server.get("/_/user?username=bob").0, Status::NotFound);
+}
+
+#[test]
+fn duplicate_username_conflicts() {
+    let server = TestServer::new();
+    server.register("alice");
+    let (public, _) = pkc::gen_keypair();
+    let (status, _) = server
+        .post("/_/user", json!({ "publicKey": base64::encode(&public.0), "username": "alice" }));
+    assert_eq!(status, Status::Conflict);
+
+    // Of two registrations of a name awaiting confirmation, only the first to
+    // be confirmed creates the user.
+    let tokens = ["bob", "Bob"]
+        .iter()
+        .map(|username| {
+            let (public, secret) = pkc::gen_keypair();
+            let user = TestUser { username: username.to_string(), public, secret };
+            let (status, body) = server.post(
+                "/_/user",
+                json!({ "publicKey": base64::encode(&user.public.0), "username": username }),
+            );
+            assert_eq!(status, Status::Ok);
+            server.open_token(&serde_json::from_str(&body).unwrap(), &user)
+        })
+        .collect::<Vec<_>>();
+    let confirm = |username: &str, token: &str| {
+        server.post("/_/user/confirm", json!({ "username": username, "decryptedToken": token }))
+    };
+    assert_eq!(confirm("bob", &tokens[0]).0, Status::Created);
+    let (status, body) = confirm("Bob", &tokens[1]);
+    assert_eq!(status, Status::Conflict);
+    assert_eq!(error_code(&body), "username_taken");
+}
+
+#[test]
+fn registration_requires_secret_key() {
+    let server = TestServer::new();
+    let (public, secret) = pkc::gen_keypair();
+    let user = TestUser { username: "alice".to_string(), public, secret };
+    let begin = || {
+        let (status, body) = server.post(
+            "/_/user",
+            json!({ "publicKey": base64::encode(&public.0), "username": "alice" }),
+        );
+        assert_eq!(status, Status::Ok);
+        server.open_token(&serde_json::from_str(&body).unwrap(), &user)
+    };
+    let confirm = |username: &str, token: &str| {
+        server.post("/_/user/confirm", json!({ "username": username, "decryptedToken": token }))
+    };
+
+    // Someone without the secret key cannot return the token.
+    begin();
+    let (status, body) = confirm("alice", &base64::encode(&[0; 32]));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+    assert_eq!(server.get("/_/user?username=alice").0, Status::NotFound);
+
+    // Tokens are bound to the username, and time out.
+    let token = begin();
+    assert_eq!(confirm("bob", &token).0, Status::Forbidden);
+    server.execute_sql("UPDATE PendingRegistrations SET Timeout = '2000-01-01 00:00:00'");
+    let (status, body) = confirm("alice", &token);
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_expired");
+
+    // Registering the same public key, known to anyone, does not cancel a
+    // registration awaiting confirmation.
+    let token = begin();
+    for username in &["mallory", "alice"] {
+        let (status, _) = server.post(
+            "/_/user",
+            json!({ "publicKey": base64::encode(&public.0), "username": username }),
+        );
+        assert_eq!(status, Status::Ok);
+    }
+    assert_eq!(begin(), token);
+
+    // Each token may only be used once.
+    assert_eq!(confirm("alice", &token).0, Status::Created);
+    assert_eq!(confirm("alice", &token).0, Status::Forbidden);
+    let user: User = server.get_json("/_/user?username=alice");
+    assert_eq!(user.public_key, base64::encode(&public.0));
+}
+
+#[test]
+fn auth_and_registration_are_rate_limited() {
+    let server = TestServer::with_config(
+        &ServerOptions::default(),
+        json!({
+            "rate_limits": {
+                "auth": { "per_ip": 3, "per_username": 2 },
+                "register": { "per_ip": 2 },
+            },
+            "trusted_proxies": ["10.0.0.9"],
+        }),
+    );
+    let get_auth_via = |username: &str, ip: &str, real_ip: &str| {
+        server
+            .client
+            .get(format!("/_/auth?username={}&action=login", username))
+            .remote(format!("{}:8000", ip).parse().unwrap())
+            .header(Header::new("X-Real-IP", real_ip.to_string()))
+            .dispatch()
+    };
+    let get_auth = |username: &str, ip: &str| get_auth_via(username, ip, ip);
+    let assert_limited = |response: LocalResponse| {
+        assert_eq!(response.status(), Status::TooManyRequests);
+        let retry_after = response.headers().get_one("Retry-After").unwrap();
+        assert!((1..=30).contains(&retry_after.parse::<u32>().unwrap()));
+    };
+
+    server.register("alice");
+    server.register("bob");
+    let (public, _) = pkc::gen_keypair();
+    let (status, body) = server
+        .post("/_/user", json!({ "publicKey": base64::encode(&public.0), "username": "carol" }));
+    assert_eq!(status, Status::TooManyRequests);
+    assert_eq!(error_code(&body), "rate_limited");
+
+    // Each username and each client has its own allowance.
+    assert_eq!(get_auth("alice", "10.0.0.1").status(), Status::Ok);
+    assert_eq!(get_auth("alice", "10.0.0.1").status(), Status::Ok);
+    assert_limited(get_auth("alice", "10.0.0.2"));
+    assert_limited(get_auth("ALICE", "10.0.0.2"));
+    assert_eq!(get_auth("bob", "10.0.0.2").status(), Status::Ok);
+    assert_limited(get_auth("bob", "10.0.0.2"));
+    assert_eq!(get_auth("bob", "10.0.0.1").status(), Status::Ok);
+
+    // Usernames which do not exist are not charged.
+    for _ in 0..3 {
+        assert_eq!(get_auth("nobody", "10.0.0.3").status(), Status::NotFound);
+    }
+
+    // The X-Real-IP header is only honoured from a trusted proxy.
+    assert_limited(get_auth_via("nobody", "10.0.0.3", "10.0.0.4"));
+    assert_eq!(get_auth_via("nobody", "10.0.0.9", "10.0.0.4").status(), Status::NotFound);
+    assert_limited(get_auth_via("nobody", "10.0.0.9", "10.0.0.3"));
+}
+
+#[test]
+fn auth_and_registration_require_proof_of_work() {
+    let challenge: PowChallenge = TestServer::new().get_json("/_/pow");
+    assert_eq!(challenge.difficulty, 0);
+
+    let server = TestServer::with_config(
+        &ServerOptions::default(),
+        json!({
+            "proof_of_work": {
+                "enabled": true,
+                "difficulty": 4,
+                "max_difficulty": 6,
+                "load_step": 1,
+            },
+        }),
+    );
+    let (public, secret) = pkc::gen_keypair();
+    let alice = TestUser { username: "alice".to_string(), public, secret };
+    let register = |work: Option<&str>| {
+        let mut request = server.client.post("/_/user").header(ContentType::JSON).body(
+            json!({ "publicKey": base64::encode(&alice.public.0), "username": "alice" })
+                .to_string(),
+        );
+        if let Some(work) = work {
+            request.add_header(Header::new("X-Proof-Of-Work", work.to_string()));
+        }
+        let mut response = request.dispatch();
+        (response.status(), response.body_string().unwrap_or_default())
+    };
+    let get_auth = |work: &str| {
+        let mut response = server
+            .client
+            .get("/_/auth?username=alice&action=login")
+            .header(Header::new("X-Proof-Of-Work", work.to_string()))
+            .dispatch();
+        (response.status(), response.body_string().unwrap_or_default())
+    };
+
+    let (status, body) = register(None);
+    assert_eq!(status, Status::PreconditionRequired);
+    assert_eq!(error_code(&body), "pow_required");
+    let (status, body) = register(Some("not a solution"));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "pow_invalid");
+
+    let challenge: PowChallenge = server.get_json("/_/pow");
+    assert_eq!(challenge.difficulty, 4);
+    let unsolved = (0u64..)
+        .map(|nonce| format!("{}:{}", challenge.salt, nonce))
+        .find(|solution| leading_zero_bits(solution) < 4)
+        .unwrap();
+    assert_eq!(register(Some(&unsolved)).0, Status::Forbidden);
+
+    let solution = solve(&challenge);
+    let (status, body) = register(Some(&solution));
+    assert_eq!(status, Status::Ok);
+    let token = server.open_token(&serde_json::from_str(&body).unwrap(), &alice);
+    let (status, _) =
+        server.post("/_/user/confirm", json!({ "username": "alice", "decryptedToken": token }));
+    assert_eq!(status, Status::Created);
+
+    // Each challenge may only be solved once.
+    let (status, body) = get_auth(&solution);
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "pow_invalid");
+
+    // The difficulty rises as proofs are accepted, up to the maximum.
+    let mut difficulties = Vec::new();
+    for _ in 0..4 {
+        let challenge: PowChallenge = server.get_json("/_/pow");
+        difficulties.push(challenge.difficulty);
+        assert_eq!(get_auth(&solve(&challenge)).0, Status::Ok);
+    }
+    assert_eq!(difficulties, vec![5, 5, 6, 6]);
+}
+
+#[test]
+fn proof_of_work_key_is_shared() {
+    let key = base64::encode(&sodiumoxide::crypto::auth::gen_key().0);
+    let config = json!({
+        "proof_of_work": { "enabled": true, "difficulty": 1 },
+        "pow_key": key,
+    });
+    let issuer = TestServer::with_config(&ServerOptions::default(), config.clone());
+    let verifier = TestServer::with_config(&ServerOptions::default(), config);
+    let (public, _) = pkc::gen_keypair();
+
+    let challenge: PowChallenge = issuer.get_json("/_/pow");
+    let response = verifier
+        .client
+        .post("/_/user")
+        .header(ContentType::JSON)
+        .header(Header::new("X-Proof-Of-Work", solve(&challenge)))
+        .body(json!({ "publicKey": base64::encode(&public.0), "username": "alice" }).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
+
+#[test]
+fn auth_challenge_response() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+
+    let proof = server.proof(&alice, "login");
+    let (status, body) =
+        server.post("/_/auth", json!({ "decryptedToken": proof, "username": "alice" }));
+    assert_eq!(status, Status::Ok);
+    assert_eq!(body, "true");
+
+    // Each token may only be used once.
+    let (status, body) =
+        server.post("/_/auth", json!({ "decryptedToken": proof, "username": "alice" }));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+}
+
+#[test]
+fn auth_tokens_are_single_use_and_bound_to_an_action() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let login = |proof: &str| {
+        server.post("/_/auth", json!({ "decryptedToken": proof, "username": "alice" })).0
+    };
+
+    // Each request issues a new token, and each is accepted once.
+    let first = server.proof(&alice, "login");
+    let second = server.proof(&alice, "login");
+    assert_ne!(first, second);
+    assert_eq!(login(&second), Status::Ok);
+    assert_eq!(login(&first), Status::Ok);
+    assert_eq!(login(&first), Status::Forbidden);
+
+    // A token is only accepted for the action it was requested for.
+    let post_proof = server.proof(&alice, "create_post");
+    assert_eq!(login(&post_proof), Status::Forbidden);
+    let mut body = encrypt_post(&alice, "Hello", &[&alice]);
+    body["username"] = json!("alice");
+    body["proof"] = json!(post_proof);
+    assert_eq!(server.post("/_/post", body).0, Status::Ok);
+
+    let (status, body) = server.get("/_/auth?username=alice");
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(error_code(&body), "bad_request");
+    let (status, _) = server.get("/_/auth?username=alice&action=everything");
+    assert_eq!(status, Status::BadRequest);
+
+    // Only the most recent tokens for an action are kept.
+    let proofs = (0..9).map(|_| server.proof(&alice, "login")).collect::<Vec<_>>();
+    assert_eq!(login(&proofs[0]), Status::Forbidden);
+    assert_eq!(login(&proofs[1]), Status::Ok);
+}
+
+#[test]
+fn stateless_challenges_are_not_stored() {
+    let challenge_key = base64::encode(&sodiumoxide::crypto::auth::gen_key().0);
+    let config = json!({ "auth_challenges": "stateless", "challenge_key": challenge_key });
+    let server = TestServer::with_config(&ServerOptions::default(), config.clone());
+    let alice = server.register("alice");
+    let login = |server: &TestServer, proof: &str| {
+        server.post("/_/auth", json!({ "decryptedToken": proof, "username": "alice" })).0
+    };
+
+    let proof = server.proof(&alice, "login");
+    assert_eq!(login(&server, &proof), Status::Ok);
+    assert_eq!(login(&server, &proof), Status::Forbidden);
+    let mut tampered = base64::decode(&server.proof(&alice, "login")).unwrap();
+    tampered[0] ^= 1;
+    assert_eq!(login(&server, &base64::encode(&tampered)), Status::Forbidden);
+    assert_eq!(login(&server, &server.proof(&alice, "create_post")), Status::Forbidden);
+    server.create_post(&alice, "Hello", &[&alice]);
+
+    let conn = SqliteConnection::establish(server.database.to_str().unwrap()).unwrap();
+    let stored: i64 = schema::Auth::table.count().get_result(&conn).unwrap();
+    assert_eq!(stored, 0);
+
+    // Servers sharing the challenge key accept each other's tokens.
+    let other = TestServer::with_config(&ServerOptions::default(), config);
+    let alice = other.register_user(alice);
+    assert_eq!(login(&other, &server.proof(&alice, "login")), Status::Ok);
+}
+
+#[test]
+fn auth_rejects_expired_proof() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let proof = server.proof(&alice, "login");
+    server.execute_sql("UPDATE Auth SET Timeout = '2000-01-01 00:00:00'");
+
+    let (status, body) =
+        server.post("/_/auth", json!({ "decryptedToken": proof, "username": "alice" }));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_expired");
+}
+
+#[test]
+fn auth_rejects_wrong_proof() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    server.proof(&alice, "login");
+
+    let wrong = base64::encode(&[0; 32]);
+    let (status, body) =
+        server.post("/_/auth", json!({ "decryptedToken": wrong, "username": "alice" }));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+
+    let (status, body) = server.get("/_/auth?username=bob&action=login");
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error_code(&body), "user_not_found");
+}
+
+#[test]
+fn malformed_requests_have_json_errors() {
+    let server = TestServer::new();
+
+    let (status, body) = server.get("/_/no_such_route");
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error_code(&body), "not_found");
+
+    let (status, body) = server.post("/_/auth", json!({ "username": "alice" }));
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(error_code(&body), "bad_request");
+}
+
+#[test]
+fn created_post_is_listed_for_its_readers() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let carol = server.register("carol");
+
+    let created = server.create_post(&alice, "Hello, Bob", &[&alice, &bob]);
+    assert!(created.failed_readers.is_empty());
+
+    let feed = server.noa(&bob);
+    assert_eq!(feed.noas.len(), 1);
+    assert_eq!(feed.next_cursor, None);
+    let noa = &feed.noas[0];
+    assert_eq!(noa.post.post_id, created.post_id);
+    assert_eq!(noa.post.username, "alice");
+    assert_eq!(noa.post.public_key, base64::encode(&alice.public.0));
+    let mut readers = noa.all_readers.clone();
+    readers.sort();
+    assert_eq!(readers, vec!["alice", "bob"]);
+    assert_eq!(decrypt_post(noa, &alice, &bob).as_ref().map(String::as_str), Some("Hello, Bob"));
+
+    assert_eq!(server.noa(&alice).noas.len(), 1);
+    assert!(server.noa(&carol).noas.is_empty());
+}
+
+#[test]
+fn created_post_reports_failed_readers() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let (public, secret) = pkc::gen_keypair();
+    let nobody = TestUser { username: "nobody".to_string(), public, secret };
+
+    let first = server.create_post(&alice, "Hello", &[&alice]);
+    let created = server.create_post(&alice, "Hello, Bob", &[&alice, &nobody, &bob, &bob]);
+    assert_ne!(created.post_id, first.post_id);
+    assert_eq!(created.failed_readers, vec!["nobody", "bob"]);
+
+    let feed = server.noa(&bob);
+    assert_eq!(feed.noas.len(), 1);
+    assert_eq!(feed.noas[0].post.post_id, created.post_id);
+    assert_eq!(
+        decrypt_post(&feed.noas[0], &alice, &bob).as_ref().map(String::as_str),
+        Some("Hello, Bob")
+    );
+}
+
+#[test]
+fn feed_is_paged_by_cursor() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let session = server.session(&bob);
+    let page = |cursor: Option<&str>, limit: i64| -> NoaOuterResponse {
+        let mut uri = format!("/_/noa?username=bob&limit={}", limit);
+        if let Some(cursor) = cursor {
+            uri += &format!("&cursor={}", cursor);
+        }
+        let (status, body) = server.get_with_session(&uri, &session);
+        assert_eq!(status, Status::Ok, "GET {} failed: {}", uri, body);
+        serde_json::from_str(&body).unwrap()
+    };
+    let ids = |feed: &NoaOuterResponse| -> Vec<i32> {
+        feed.noas.iter().map(|noa| noa.post.post_id).collect()
+    };
+
+    // Posts made within the same second are ordered by their ID.
+    let mut posts = (0..5)
+        .map(|i| server.create_post(&alice, &format!("Post {}", i), &[&bob]).post_id)
+        .collect::<Vec<_>>();
+    posts.reverse();
+
+    let first = page(None, 2);
+    assert_eq!(ids(&first), posts[..2].to_vec());
+
+    // A post made between requests does not disturb later pages.
+    server.create_post(&alice, "Newest", &[&bob]);
+    let second = page(first.next_cursor.as_ref().map(String::as_str), 2);
+    assert_eq!(ids(&second), posts[2..4].to_vec());
+    let third = page(second.next_cursor.as_ref().map(String::as_str), 2);
+    assert_eq!(ids(&third), posts[4..].to_vec());
+    assert_eq!(third.next_cursor, None);
+
+    let (status, body) = server.get_with_session("/_/noa?username=bob&limit=0", &session);
+    assert_eq!(status, Status::BadRequest);
+    assert_eq!(error_code(&body), "bad_request");
+    let (status, _) = server.get_with_session("/_/noa?username=bob&cursor=invalid", &session);
+    assert_eq!(status, Status::BadRequest);
+}
+
+#[test]
+fn noa_requires_identity_of_reader() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    server.create_post(&alice, "Hello", &[&alice]);
+
+    let (status, body) = server.get("/_/noa?username=alice");
+    assert_eq!(status, Status::Unauthorized);
+    assert_eq!(error_code(&body), "auth_required");
+
+    let (status, body) = server.get_with_session("/_/noa?username=alice", &server.session(&bob));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+
+    let (status, body) = server
+        .get(&format!("/_/noa?username=alice&proof={}", urlencode(&base64::encode(&[0; 32]))));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+
+    let proof = server.proof(&alice, "read_feed");
+    let feed: NoaOuterResponse =
+        server.get_json(&format!("/_/noa?username=alice&proof={}", urlencode(&proof)));
+    assert_eq!(feed.noas.len(), 1);
+}
+
+#[test]
+fn post_requires_valid_proof() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    server.proof(&alice, "create_post");
+
+    let (status, _) = server.post(
+        "/_/post",
+        json!({
+            "content": "",
+            "nonce": "",
+            "username": "alice",
+            "proof": base64::encode(&[0; 32]),
+            "publicKey": "",
+            "publicKeyNonce": "",
+            "noaEncryptedKeys": [],
+        }),
+    );
+    assert_eq!(status, Status::Forbidden);
+    assert!(server.noa(&alice).noas.is_empty());
+}
+
+#[test]
+fn session_authorises_writes() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let session = server.session(&alice);
+    let post = |username: &str| {
+        json!({
+            "content": "",
+            "nonce": "",
+            "username": username,
+            "publicKey": "",
+            "publicKeyNonce": "",
+            "noaEncryptedKeys": [],
+        })
+    };
+
+    // A session is reusable until it expires.
+    for _ in 0..2 {
+        let (status, _) = server.post_with_session("/_/post", &session, post("alice"));
+        assert_eq!(status, Status::Ok);
+    }
+
+    // It only authorises the user it was issued to.
+    let (status, body) = server.post_with_session("/_/post", &session, post("bob"));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+    let (status, body) = server.post_with_session("/_/post", &server.session(&bob), post("alice"));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+
+    let mut forged = base64::decode(&session).unwrap();
+    forged[3] ^= 1;
+    let (status, body) =
+        server.post_with_session("/_/post", &base64::encode(&forged), post("alice"));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+
+    let (status, body) = server.post("/_/post", post("alice"));
+    assert_eq!(status, Status::Unauthorized);
+    assert_eq!(error_code(&body), "auth_required");
+
+    // Destructive and account level actions require a fresh proof.
+    let (status, body) = server.delete_with_session("/_/user?username=alice", &session);
+    assert_eq!(status, Status::Unauthorized);
+    assert_eq!(error_code(&body), "proof_required");
+    let (status, body) = server.put_with_session(
+        "/_/user/username",
+        &session,
+        json!({ "username": "alice", "newUsername": "alicia" }),
+    );
+    assert_eq!(status, Status::Unauthorized);
+    assert_eq!(error_code(&body), "proof_required");
+}
+
+#[test]
+fn author_can_rekey_post_to_revoke_readers() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let carol = server.register("carol");
+    let post_id = server.create_post(&alice, "Hello", &[&alice, &bob, &carol]).post_id;
+    let rekey = |readers: &[&TestUser]| {
+        let mut body = encrypt_post(&alice, "Hello again", readers);
+        body["postId"] = json!(post_id);
+        body
+    };
+
+    // Only the author may re-key the post.
+    let (status, _) =
+        server.put_with_session("/_/post/rekey", &server.session(&bob), rekey(&[&bob]));
+    assert_eq!(status, Status::Forbidden);
+
+    let (status, body) =
+        server.put_with_session("/_/post/rekey", &server.session(&alice), rekey(&[&alice, &bob]));
+    assert_eq!(status, Status::Ok);
+    let rekeyed: PostCreatedResponse = serde_json::from_str(&body).unwrap();
+    assert_eq!(rekeyed, PostCreatedResponse { post_id, failed_readers: vec![] });
+
+    assert!(server.noa(&carol).noas.is_empty());
+    let noa = server.noa(&bob).noas.remove(0);
+    assert_eq!(noa.post.post_id, post_id);
+    let mut readers = noa.all_readers.clone();
+    readers.sort();
+    assert_eq!(readers, vec!["alice", "bob"]);
+    assert_eq!(decrypt_post(&noa, &alice, &bob).as_ref().map(String::as_str), Some("Hello again"));
+
+    let mut missing = rekey(&[&alice]);
+    missing["postId"] = json!(post_id + 1);
+    let (status, body) = server.put_with_session("/_/post/rekey", &server.session(&alice), missing);
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error_code(&body), "post_not_found");
+}
+
+#[test]
+fn author_can_add_readers() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let (public, secret) = pkc::gen_keypair();
+    let nobody = TestUser { username: "nobody".to_string(), public, secret };
+    let post_id = server.create_post(&alice, "Hello", &[&alice]).post_id;
+
+    // Share the existing post key, as the client would.
+    let noa = server.noa(&alice).noas.remove(0);
+    let post_secret = pkc::open(
+        &base64::decode(&noa.encrypted_secret_key).unwrap(),
+        &pkc::Nonce::from_slice(&base64::decode(&noa.nonce).unwrap()).unwrap(),
+        &alice.public,
+        &alice.secret,
+    )
+    .unwrap();
+    let add = |readers: &[&TestUser]| {
+        let noa_encrypted_keys = readers
+            .iter()
+            .map(|reader| {
+                let nonce = pkc::gen_nonce();
+                json!({
+                    "username": reader.username,
+                    "encryptedSecretKey": base64::encode(
+                        &pkc::seal(&post_secret, &nonce, &reader.public, &alice.secret)
+                    ),
+                    "nonce": base64::encode(&nonce),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({ "postId": post_id, "noaEncryptedKeys": noa_encrypted_keys })
+    };
+
+    let (status, _) =
+        server.post_with_session("/_/post/readers", &server.session(&bob), add(&[&bob]));
+    assert_eq!(status, Status::Forbidden);
+
+    let (status, body) = server.post_with_session(
+        "/_/post/readers",
+        &server.session(&alice),
+        add(&[&bob, &nobody, &alice]),
+    );
+    assert_eq!(status, Status::Ok);
+    let added: PostCreatedResponse = serde_json::from_str(&body).unwrap();
+    assert_eq!(added.failed_readers, vec!["nobody", "alice"]);
+
+    let noa = server.noa(&bob).noas.remove(0);
+    assert_eq!(noa.post.post_id, post_id);
+    assert_eq!(decrypt_post(&noa, &alice, &bob).as_ref().map(String::as_str), Some("Hello"));
+}
+
+#[test]
+fn author_can_delete_post() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let post_id = server.create_post(&alice, "Oops", &[&alice, &bob]).post_id;
+    let delete = |user: &TestUser| {
+        let proof = server.proof(user, "delete_post");
+        server.delete(&format!("/_/post?post_id={}&proof={}", post_id, urlencode(&proof)))
+    };
+
+    let (status, _) = delete(&bob);
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(server.noa(&bob).noas.len(), 1);
+
+    let (status, body) = server
+        .delete_with_session(&format!("/_/post?post_id={}", post_id), &server.session(&alice));
+    assert_eq!(status, Status::Unauthorized);
+    assert_eq!(error_code(&body), "proof_required");
+
+    let (status, _) = delete(&alice);
+    assert_eq!(status, Status::Ok);
+    assert!(server.noa(&alice).noas.is_empty());
+    assert!(server.noa(&bob).noas.is_empty());
+
+    let (status, body) = delete(&alice);
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error_code(&body), "post_not_found");
+}
+
+#[test]
+fn reader_can_hide_post() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let carol = server.register("carol");
+    let post_id = server.create_post(&alice, "Hello", &[&alice, &bob, &carol]).post_id;
+    let uri = |username: &str| format!("/_/noa?username={}&post_id={}", username, post_id);
+
+    // Bob cannot hide the post from Carol's feed.
+    let (status, _) = server.delete_with_session(&uri("carol"), &server.session(&bob));
+    assert_eq!(status, Status::Forbidden);
+
+    let (status, _) = server.delete_with_session(&uri("bob"), &server.session(&bob));
+    assert_eq!(status, Status::Ok);
+    assert!(server.noa(&bob).noas.is_empty());
+    let noa = server.noa(&carol).noas.remove(0);
+    let mut readers = noa.all_readers.clone();
+    readers.sort();
+    assert_eq!(readers, vec!["alice", "carol"]);
+
+    let (status, body) = server.delete_with_session(&uri("bob"), &server.session(&bob));
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error_code(&body), "post_not_found");
+}
+
+#[test]
+fn user_can_delete_account() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    server.create_post(&alice, "From Alice", &[&alice, &bob]);
+    let bobs_post = server.create_post(&bob, "From Bob", &[&alice, &bob]).post_id;
+    let session = server.session(&alice);
+    // Leave an outstanding authentication token behind.
+    server.get("/_/auth?username=alice&action=login");
+    let delete =
+        |proof: &str| server.delete(&format!("/_/user?username=alice&proof={}", urlencode(proof)));
+
+    let (status, _) = delete(&server.proof(&bob, "delete_user"));
+    assert_eq!(status, Status::Forbidden);
+
+    let (status, _) = delete(&server.proof(&alice, "delete_user"));
+    assert_eq!(status, Status::Ok);
+    let (status, body) = server.get("/_/user?username=alice");
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error_code(&body), "user_not_found");
+
+    let feed = server.noa(&bob);
+    assert_eq!(feed.noas.len(), 1);
+    assert_eq!(feed.noas[0].post.post_id, bobs_post);
+    assert_eq!(feed.noas[0].all_readers, vec!["bob"]);
+
+    // No rows referring to Alice remain, so her username is free again. Her
+    // sessions are not accepted for whoever takes it.
+    server.register("alice");
+    let (status, body) = server.post_with_session(
+        "/_/post",
+        &session,
+        json!({
+            "content": "",
+            "nonce": "",
+            "username": "alice",
+            "publicKey": "",
+            "publicKeyNonce": "",
+            "noaEncryptedKeys": [],
+        }),
+    );
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+}
+
+#[test]
+fn user_can_export_account() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let carol = server.register("carol");
+    server.create_post(&alice, "From Alice", &[&alice, &bob]);
+    server.create_post(&bob, "From Bob", &[&bob, &alice]);
+    server.create_post(&carol, "From Carol", &[&carol, &bob]);
+
+    let export = |proof: &str| {
+        server.get(&format!("/_/user/export?username=alice&proof={}", urlencode(proof)))
+    };
+
+    let (status, _) = export(&server.proof(&bob, "export_user"));
+    assert_eq!(status, Status::Forbidden);
+
+    let (status, body) = export(&server.proof(&alice, "export_user"));
+    assert_eq!(status, Status::Ok);
+    let export: UserExport = serde_json::from_str(&body).unwrap();
+    assert_eq!(export.user.username, "alice");
+    assert_eq!(export.posts.len(), 2);
+    let mut noas =
+        export.noas.iter().map(|noa| (noa.post_id, noa.username.as_str())).collect::<Vec<_>>();
+    noas.sort();
+    let (alices_post, bobs_post) = (export.posts[1].post_id, export.posts[0].post_id);
+    assert_eq!(noas, vec![(alices_post, "alice"), (alices_post, "bob"), (bobs_post, "alice")]);
+
+    // Every post can be decrypted from the archive alone.
+    let mut contents = export
+        .posts
+        .iter()
+        .map(|post| {
+            let noa = export
+                .noas
+                .iter()
+                .find(|noa| noa.post_id == post.post_id && noa.username == "alice")
+                .unwrap();
+            let noa = NoaResponse {
+                post: post.clone(),
+                encrypted_secret_key: noa.encrypted_secret_key.clone(),
+                nonce: noa.nonce.clone(),
+                all_readers: vec![],
+            };
+            let author = if post.username == "alice" { &alice } else { &bob };
+            decrypt_post(&noa, author, &alice).unwrap()
+        })
+        .collect::<Vec<_>>();
+    contents.sort();
+    assert_eq!(contents, vec!["From Alice", "From Bob"]);
+}
+
+#[test]
+fn user_can_rotate_key() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    let carol = server.register("carol");
+    server.create_post(&alice, "From Alice", &[&alice, &bob]);
+    server.create_post(&bob, "From Bob", &[&bob, &alice]);
+    server.create_post(&carol, "From Carol", &[&carol, &bob]);
+    let (public, secret) = pkc::gen_keypair();
+    let replacement = TestUser { username: "alice".to_string(), public, secret };
+    let export = |server: &TestServer| -> UserExport {
+        let proof = server.proof(&alice, "export_user");
+        server.get_json(&format!("/_/user/export?username=alice&proof={}", urlencode(&proof)))
+    };
+    let session = server.session(&alice);
+    let put_key = |mut body: serde_json::Value, prover: &TestUser| {
+        body["proof"] = json!(server.proof(prover, "rotate_key"));
+        server.put("/_/user/key", body)
+    };
+
+    // A post shared after the export would be left under the previous key.
+    let stale = rotate_key(&export(&server), &alice, &replacement, &[&bob]);
+    server.create_post(&bob, "Since", &[&bob, &alice]);
+    let (status, body) = put_key(stale, &alice);
+    assert_eq!(status, Status::Conflict);
+    assert_eq!(error_code(&body), "conflict");
+
+    let body = rotate_key(&export(&server), &alice, &replacement, &[&bob]);
+    let (status, _) = put_key(body.clone(), &bob);
+    assert_eq!(status, Status::Forbidden);
+    let (status, error) = server.put_with_session("/_/user/key", &session, body.clone());
+    assert_eq!(status, Status::Unauthorized);
+    assert_eq!(error_code(&error), "proof_required");
+    let (status, _) = put_key(body, &alice);
+    assert_eq!(status, Status::Ok);
+    let user: User = server.get_json("/_/user?username=alice");
+    assert_eq!(user.public_key, base64::encode(&replacement.public.0));
+
+    // Sessions issued with the previous key have ended.
+    let (status, body) = server.get_with_session("/_/noa?username=alice", &session);
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+
+    // Every post is readable by its readers under the new key alone.
+    let mut contents = server
+        .noa(&replacement)
+        .noas
+        .iter()
+        .map(|noa| {
+            let author = if noa.post.username == "alice" { &replacement } else { &bob };
+            decrypt_post(noa, author, &replacement).unwrap()
+        })
+        .collect::<Vec<_>>();
+    contents.sort();
+    assert_eq!(contents, vec!["From Alice", "From Bob", "Since"]);
+    let feed = server.noa(&bob);
+    let noa = feed.noas.iter().find(|noa| noa.post.username == "alice").unwrap();
+    assert_eq!(decrypt_post(noa, &replacement, &bob).unwrap(), "From Alice");
+}
+
+#[test]
+fn user_can_change_username() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    server.create_post(&alice, "From Alice", &[&alice, &bob]);
+    let session = server.session(&alice);
+    let rename = |from: &str, to: &str, user: &TestUser| {
+        server.rename(&TestUser { username: from.to_string(), ..user.clone() }, to)
+    };
+
+    let (status, body) = rename("alice", "bob", &alice);
+    assert_eq!(status, Status::Conflict);
+    assert_eq!(error_code(&body), "username_taken");
+    let (status, _) = rename("alice", "alicia", &alice);
+    assert_eq!(status, Status::Ok);
+    let user: User = server.get_json("/_/user?username=alicia");
+    assert_eq!(user.public_key, base64::encode(&alice.public.0));
+    let feed = server.noa(&bob);
+    assert_eq!(feed.noas[0].post.username, "alicia");
+
+    // The previous username redirects to the new one, and cannot be taken.
+    let response = server.client.get("/_/auth?username=alice&action=login").dispatch();
+    assert_eq!(response.status(), Status::TemporaryRedirect);
+    assert_eq!(
+        response.headers().get_one("Location"),
+        Some("/_/auth?username=alicia&action=login")
+    );
+    let response = server
+        .client
+        .get("/_/noa?username=alice&limit=5")
+        .header(Header::new("Authorization", format!("Bearer {}", session)))
+        .dispatch();
+    assert_eq!(response.headers().get_one("Location"), Some("/_/noa?username=alicia&limit=5"));
+    let (status, body) = server.get("/_/user?username=alice");
+    assert_eq!(status, Status::TemporaryRedirect);
+    assert_eq!(error_code(&body), "user_renamed");
+    let (status, body) = server.post(
+        "/_/user",
+        json!({ "publicKey": base64::encode(&pkc::gen_keypair().0 .0), "username": "alice" }),
+    );
+    assert_eq!(status, Status::Conflict);
+    assert_eq!(error_code(&body), "username_taken");
+    let (status, _) = rename("bob", "alice", &bob);
+    assert_eq!(status, Status::Conflict);
+
+    // Only the user it is reserved for may rename back to it.
+    let (status, _) = rename("alicia", "alice", &alice);
+    assert_eq!(status, Status::Ok);
+    let (status, _) = server.get("/_/user?username=alicia");
+    assert_eq!(status, Status::TemporaryRedirect);
+
+    // Once the reservation expires, the username is free.
+    server.execute_sql("UPDATE UsernameReservations SET Expires = '2000-01-01 00:00:00'");
+    let (status, body) = server.get("/_/user?username=alicia");
+    assert_eq!(status, Status::NotFound);
+    assert_eq!(error_code(&body), "user_not_found");
+    server.register("alicia");
+}
+
+#[test]
+fn maintenance_purges_expired_state() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let (status, _) = server.rename(&alice, "alicia");
+    assert_eq!(status, Status::Ok);
+    server.proof(&TestUser { username: "alicia".to_string(), ..alice }, "login");
+    let mut tokens = Vec::new();
+    for username in &["bob", "carol"] {
+        let (public, secret) = pkc::gen_keypair();
+        let user = TestUser { username: username.to_string(), public, secret };
+        let (status, body) = server.post(
+            "/_/user",
+            json!({ "publicKey": base64::encode(&user.public.0), "username": username }),
+        );
+        assert_eq!(status, Status::Ok);
+        tokens.push(server.open_token(&serde_json::from_str(&body).unwrap(), &user));
+    }
+    server.execute_sql("UPDATE Auth SET Timeout = '2000-01-01 00:00:00'");
+    server.execute_sql(
+        "UPDATE PendingRegistrations SET Timeout = '2000-01-01 00:00:00' WHERE Username = 'bob'",
+    );
+    server.execute_sql("UPDATE UsernameReservations SET Expires = '2000-01-01 00:00:00'");
+
+    let conn = SqliteConnection::establish(server.database.to_str().unwrap()).unwrap();
+    let expected = PurgeCounts { auth: 1, pending_registrations: 1, username_reservations: 1 };
+    assert_eq!(maintenance::run(&conn).unwrap(), expected);
+    assert_eq!(maintenance::run(&conn).unwrap(), PurgeCounts::default());
+
+    // State which has not expired is kept.
+    let (status, body) =
+        server.post("/_/user/confirm", json!({ "username": "bob", "decryptedToken": tokens[0] }));
+    assert_eq!(status, Status::Forbidden);
+    assert_eq!(error_code(&body), "auth_invalid");
+    let (status, _) =
+        server.post("/_/user/confirm", json!({ "username": "carol", "decryptedToken": tokens[1] }));
+    assert_eq!(status, Status::Created);
+}
+
+#[test]
+fn registration_validates_username_and_public_key() {
+    let server = TestServer::new();
+    let register = |username: &str, public_key: &[u8]| {
+        server.post(
+            "/_/user",
+            json!({ "publicKey": base64::encode(public_key), "username": username }),
+        )
+    };
+    let public_key = || pkc::gen_keypair().0 .0;
+
+    for username in &["", "al ice", "alice?", "al/ice", &"a".repeat(101), &"ﷺ".repeat(10)] {
+        let (status, body) = register(username, &public_key());
+        assert_eq!(status, Status::BadRequest, "{:?} should be refused", username);
+        assert_eq!(error_code(&body), "invalid_username");
+    }
+    for key in &[&b"short"[..], &[0; 33]] {
+        let (status, body) = register("alice", key);
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(error_code(&body), "invalid_public_key");
+    }
+    let (status, _) = register(&"a".repeat(100), &public_key());
+    assert_eq!(status, Status::Ok);
+
+    // Usernames are unique ignoring case and compatibility characters.
+    let alice = server.register("alice");
+    for username in &["Alice", "ALICE", "ａｌｉｃｅ"] {
+        let (status, body) = register(username, &public_key());
+        assert_eq!(status, Status::Conflict, "{:?} should be taken", username);
+        assert_eq!(error_code(&body), "username_taken");
+    }
+    let (status, body) = register("bob", &alice.public.0);
+    assert_eq!(status, Status::Conflict);
+    assert_eq!(error_code(&body), "conflict");
+
+    // Usernames are stored in Normalisation Form C.
+    server.register("e\u{301}mile");
+    let user: User = server.get_json("/_/user?username=%C3%A9mile");
+    assert_eq!(user.username, "\u{e9}mile");
+
+    // Users are looked up ignoring case and normalisation.
+    let user: User = server.get_json("/_/user?username=e%CC%81mile");
+    assert_eq!(user.username, "\u{e9}mile");
+    let user: User = server.get_json("/_/user?username=ALICE");
+    assert_eq!(user.username, "alice");
+    let proof = server.proof(&TestUser { username: "ALICE".to_string(), ..alice.clone() }, "login");
+    let (status, _) =
+        server.post("/_/auth", json!({ "decryptedToken": proof, "username": "Alice" }));
+    assert_eq!(status, Status::Ok);
+
+    // A user may change the case of their own username.
+    let (status, _) = server.rename(&alice, "Alice");
+    assert_eq!(status, Status::Ok);
+    let (status, _) = server.get("/_/user?username=Alice");
+    assert_eq!(status, Status::Ok);
+}
+
+#[test]
+fn user_can_change_case_of_username() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+
+    let (status, _) = server.rename(&alice, "Alice");
+    assert_eq!(status, Status::Ok);
+    let user: User = server.get_json("/_/user?username=alice");
+    assert_eq!(user.username, "Alice");
+
+    // The username is still the user's own, so nothing is reserved.
+    let conn = SqliteConnection::establish(server.database.to_str().unwrap()).unwrap();
+    let reserved: i64 = schema::UsernameReservations::table.count().get_result(&conn).unwrap();
+    assert_eq!(reserved, 0);
+    let alice = TestUser { username: "Alice".to_string(), ..alice };
+    let (status, _) = server.rename(&alice, "alicia");
+    assert_eq!(status, Status::Ok);
+    let (status, _) = server.get("/_/user?username=Alice");
+    assert_eq!(status, Status::TemporaryRedirect);
+}
+
+#[test]
+fn author_can_edit_post() {
+    let server = TestServer::new();
+    let alice = server.register("alice");
+    let bob = server.register("bob");
+    server.create_post(&alice, "Hello, Bob", &[&alice, &bob]);
+    let noa = server.noa(&bob).noas.remove(0);
+
+    // Re-encrypt new content with the post key, as the client would.
+    let post_secret = pkc::open(
+        &base64::decode(&noa.encrypted_secret_key).unwrap(),
+        &pkc::Nonce::from_slice(&base64::decode(&noa.nonce).unwrap()).unwrap(),
+        &alice.public,
+        &bob.secret,
+    )
+    .unwrap();
+    let post_public = pkc::SecretKey::from_slice(&post_secret).unwrap().public_key();
+    let new_nonce = pkc::gen_nonce();
+    let new_content = pkc::seal(b"Goodbye, Bob", &new_nonce, &post_public, &alice.secret);
+    let edit = |proof: String| {
+        json!({
+            "postId": noa.post.post_id,
+            "proof": proof,
+            "newContent": base64::encode(&new_content),
+            "newNonce": base64::encode(&new_nonce),
+        })
+    };
+
+    // Bob cannot prove they are the author.
+    let (status, _) = server.put("/_/post", edit(server.proof(&bob, "edit_post")));
+    assert_eq!(status, Status::Forbidden);
+
+    let (status, _) = server.put("/_/post", edit(server.proof(&alice, "edit_post")));
+    assert_eq!(status, Status::Ok);
+    let noa = server.noa(&bob).noas.remove(0);
+    assert_eq!(decrypt_post(&noa, &alice, &bob).as_ref().map(String::as_str), Some("Goodbye, Bob"));
+
+    // The author may also edit with a session instead of a proof.
+    let mut edit = edit(String::new());
+    edit.as_object_mut().unwrap().remove("proof");
+    let (status, _) = server.put_with_session("/_/post", &server.session(&bob), edit.clone());
+    assert_eq!(status, Status::Forbidden);
+    let (status, _) = server.put_with_session("/_/post", &server.session(&alice), edit);
+    assert_eq!(status, Status::Ok);
+
+    let (status, _) = server.put(
+        "/_/post",
+        json!({
+            "postId": noa.post.post_id + 1,
+            "proof": server.proof(&alice, "edit_post"),
+            "newContent": "",
+            "newNonce": "",
+        }),
+    );
+    assert_eq!(status, Status::NotFound);
+}